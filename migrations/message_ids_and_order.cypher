@@ -0,0 +1,24 @@
+// Give every message a stable id
+MATCH (m:MessageNode)
+WHERE m.id IS NULL
+SET m.id = randomUUID();
+
+// Messages stored with the hash of the message before them
+MATCH (m:MessageNode)
+WHERE m.parent_hash IS NOT NULL AND NOT (m)-[:PARENT]->()
+MATCH (p:MessageNode {chain_hash: m.parent_hash, partition: m.partition, instance: m.instance})
+WITH m, p ORDER BY p.timestamp ASC
+WITH m, collect(p)[0] AS p
+MERGE (m)-[:PARENT]->(p)
+MERGE (p)-[:NEXT]->(m);
+
+// Older messages were stored with the whole request under one trace id,
+// in the order they were sent
+MATCH (m:MessageNode)
+WITH m ORDER BY m.timestamp ASC, id(m) ASC
+WITH m.trace_id AS trace_id, collect(m) AS messages
+UNWIND range(0, size(messages) - 2) AS i
+WITH messages[i] AS p, messages[i + 1] AS m
+WHERE NOT (m)-[:PARENT]->()
+MERGE (m)-[:PARENT]->(p)
+MERGE (p)-[:NEXT]->(m);
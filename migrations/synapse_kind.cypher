@@ -0,0 +1,5 @@
+// Synapses created before semantic synapses existed all link consecutive
+// messages
+MATCH (:MessageNode)-[s:SYNAPSE]->(:MessageNode)
+WHERE s.kind IS NULL
+SET s.kind = 'sequential';
@@ -33,8 +33,14 @@ pub enum SubCommands {
     View(ViewSubCommand),
     /// Search messages by keyword or semantic similarity
     Search(crate::commands::search::SearchSubCommand),
+    /// List the branches of a conversation or walk one of them
+    Branches(crate::commands::branches::BranchesSubCommand),
     /// Ingest a message from stdin as a user MessageNode
     Ingest(IngestSubCommand),
+    /// Manage Reservoir API keys
+    Keys(KeysSubCommand),
+    /// Maintain the SYNAPSE relationships between messages
+    Synapses(SynapsesSubCommand),
 }
 
 #[derive(Parser, Debug)]
@@ -74,6 +80,9 @@ pub struct ViewSubCommand {
     /// Instance to view (defaults to partition)
     #[arg(short, long)]
     pub instance: Option<String>,
+    /// Print the id of each message, to list its branches with
+    #[arg(long)]
+    pub ids: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -89,3 +98,59 @@ pub struct IngestSubCommand {
     #[arg(long)]
     pub role: Option<String>,
 }
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Manage Reservoir API keys", long_about = None)]
+pub struct KeysSubCommand {
+    #[command(subcommand)]
+    pub action: KeysAction,
+}
+
+#[derive(Parser, Debug)]
+pub enum KeysAction {
+    /// Create a new key. The key is only shown once.
+    Create(CreateKeySubCommand),
+    /// List all keys
+    List,
+    /// Revoke a key by its id
+    Revoke(RevokeKeySubCommand),
+}
+
+#[derive(Parser, Debug)]
+pub struct CreateKeySubCommand {
+    /// A name to recognise the key by
+    #[arg(short, long)]
+    pub name: String,
+    /// Partition the key may access, can be repeated (defaults to all, "*")
+    #[arg(short, long)]
+    pub partition: Vec<String>,
+    /// Instance the key may access, can be repeated (defaults to all, "*")
+    #[arg(short, long)]
+    pub instance: Vec<String>,
+    /// Comma separated permissions out of read, write and search
+    /// (defaults to all of them)
+    #[arg(long, value_delimiter = ',')]
+    pub permissions: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct RevokeKeySubCommand {
+    /// Id of the key to revoke
+    pub id: String,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Maintain the SYNAPSE relationships between messages", long_about = None)]
+pub struct SynapsesSubCommand {
+    #[command(subcommand)]
+    pub action: SynapsesAction,
+}
+
+#[derive(Parser, Debug)]
+pub enum SynapsesAction {
+    /// Delete synapses that link messages of different partitions or
+    /// instances
+    Repair,
+    /// Delete all synapses and link every partition and instance again
+    Rebuild,
+}
@@ -0,0 +1,209 @@
+use anyhow::Error;
+use tracing::{debug, error, info};
+
+use crate::clients::anthropic::types::{MessagesRequest, MessagesResponse};
+use crate::clients::openai::model_info::ModelInfo;
+use crate::clients::openai::types::{ChatRequest, ChatResponse};
+use crate::clients::retry::parse_retry_after;
+use crate::errors::ReservoirError;
+use crate::utils::compress_system_context;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Sends a chat request to the Anthropic Messages API and returns the
+/// answer as a chat completion.
+pub async fn get_completion_message(
+    model_info: &ModelInfo,
+    chat_request: &ChatRequest,
+) -> Result<ChatResponse, Error> {
+    info!("Getting completion with Anthropic model {}", model_info.name);
+    let mut chat_request = chat_request.clone();
+    chat_request.model = model_info.name.clone();
+    chat_request.messages = compress_system_context(&chat_request.messages);
+    let request = MessagesRequest::from_chat_request(&chat_request, model_info.output_tokens as u64);
+
+    let body = serde_json::to_string(&request).map_err(|e| {
+        ReservoirError::Internal(format!("Failed to serialize messages request: {}", e))
+    })?;
+    debug!(
+        "Sending request to Anthropic API: {} - {}\nbody:\n{}",
+        model_info.name, model_info.base_url, body
+    );
+
+    let request = model_info
+        .client
+        .post(model_info.base_url.clone())
+        .header("Content-Type", "application/json")
+        .header("x-api-key", model_info.key.clone())
+        .header("anthropic-version", ANTHROPIC_VERSION);
+    let request = model_info
+        .headers
+        .iter()
+        .fold(request, |request, (name, value)| request.header(name, value))
+        .body(body);
+    let response = tokio::time::timeout(model_info.timeout, request.send())
+        .await
+        .map_err(|_| {
+            error!("Anthropic API did not answer within {:?}", model_info.timeout);
+            ReservoirError::UpstreamUnavailable(format!(
+                "Anthropic API did not answer within {} seconds",
+                model_info.timeout.as_secs()
+            ))
+        })?
+        .map_err(|e| {
+            error!("Error sending request to Anthropic API: {}", e);
+            ReservoirError::UpstreamUnavailable(format!(
+                "Failed to send request to Anthropic API: {}",
+                e
+            ))
+        })?;
+
+    let status = response.status();
+    let retry_after = parse_retry_after(response.headers());
+    let response_text = response.text().await.map_err(|e| {
+        ReservoirError::UpstreamUnavailable(format!("Failed to read response text: {}", e))
+    })?;
+    if !status.is_success() {
+        error!("Anthropic API returned error status {}: {}", status, response_text);
+        return Err(ReservoirError::Upstream {
+            status,
+            body: response_text,
+            retry_after,
+        }
+        .into());
+    }
+
+    let response: MessagesResponse = serde_json::from_str(&response_text).map_err(|e| {
+        error!("Error parsing response JSON: {}\nRaw response: {}", e, response_text);
+        ReservoirError::UpstreamUnavailable(format!(
+            "Failed to parse response JSON: {}\nRaw response: {}",
+            e, response_text
+        ))
+    })?;
+    Ok(response.into_chat_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::provider::Provider;
+    use crate::clients::openai::types::Message;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves a single canned response and hands back the raw request.
+    async fn mock_server(status: &'static str, body: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/messages", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+            loop {
+                let n = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, rest)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if rest.len() >= length {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        (url, handle)
+    }
+
+    fn model_info(base_url: String) -> ModelInfo {
+        ModelInfo {
+            input_tokens: 200_000,
+            output_tokens: 4_096,
+            name: "claude-sonnet-4-0".to_string(),
+            key: "test-key".to_string(),
+            base_url,
+            provider: Provider::Anthropic,
+            headers: Default::default(),
+            tokenizer: Default::default(),
+            capabilities: Vec::new(),
+            timeout: Duration::from_secs(120),
+            client: reqwest::Client::new(),
+            max_retries: 0,
+            fallbacks: Vec::new(),
+            larger_context: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_completion_against_mock_server() {
+        let (url, request) = mock_server(
+            "200 OK",
+            r#"{"id":"msg_1","type":"message","role":"assistant","model":"claude-sonnet-4-0",
+                "content":[{"type":"text","text":"Hello there"}],"stop_reason":"end_turn",
+                "stop_sequence":null,"usage":{"input_tokens":9,"output_tokens":3}}"#,
+        )
+        .await;
+        let chat_request = ChatRequest::new(
+            "claude-sonnet-4-0".to_string(),
+            vec![
+                Message {
+                    role: "system".to_string(),
+                    content: "Be brief".to_string(),
+                    ..Default::default()
+                },
+                Message {
+                    role: "user".to_string(),
+                    content: "Hi".to_string(),
+                    ..Default::default()
+                },
+            ],
+        );
+
+        let response = get_completion_message(&model_info(url), &chat_request)
+            .await
+            .unwrap();
+        let request = request.await.unwrap().to_lowercase();
+
+        assert_eq!(response.choices[0].message.content, "Hello there");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert!(request.contains("x-api-key: test-key"));
+        assert!(request.contains("anthropic-version: 2023-06-01"));
+        assert!(request.contains(r#""system":"be brief""#));
+        assert!(request.contains(r#""max_tokens":4096"#));
+    }
+
+    #[tokio::test]
+    async fn test_error_status_is_passed_on() {
+        let (url, _request) = mock_server(
+            "429 Too Many Requests",
+            r#"{"type":"error","error":{"type":"rate_limit_error","message":"Slow down"}}"#,
+        )
+        .await;
+        let chat_request = ChatRequest::new(
+            "claude-sonnet-4-0".to_string(),
+            vec![Message {
+                role: "user".to_string(),
+                content: "Hi".to_string(),
+                ..Default::default()
+            }],
+        );
+
+        let error = get_completion_message(&model_info(url), &chat_request)
+            .await
+            .unwrap_err();
+        let error = ReservoirError::from(error);
+
+        assert_eq!(error.status().as_u16(), 429);
+    }
+}
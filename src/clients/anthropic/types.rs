@@ -0,0 +1,759 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::clients::openai::types::{
+    ChatRequest, ChatResponse, Choice, ContentPart, ExtraFields, FunctionCall, Message, ToolCall,
+    Usage,
+};
+use crate::errors::ReservoirError;
+
+/// A request in the format of Anthropic's Messages API.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessagesRequest {
+    pub model: String,
+    pub max_tokens: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<Content>,
+    pub messages: Vec<AnthropicMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: Content,
+}
+
+/// Content is either a plain string or a list of blocks.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+impl Content {
+    fn into_blocks(self) -> Vec<ContentBlock> {
+        match self {
+            Content::Text(text) => vec![ContentBlock::Text { text }],
+            Content::Blocks(blocks) => blocks,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Blocks(blocks) => blocks
+                .iter()
+                .filter_map(|b| match b {
+                    ContentBlock::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<Content>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImageSource {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+impl ImageSource {
+    fn to_part(&self) -> ContentPart {
+        let url = match self {
+            ImageSource::Base64 { media_type, data } => {
+                format!("data:{};base64,{}", media_type, data)
+            }
+            ImageSource::Url { url } => url.clone(),
+        };
+        let mut part = ContentPart {
+            kind: "image_url".to_string(),
+            ..Default::default()
+        };
+        part.extra.insert("image_url".to_string(), json!({ "url": url }));
+        part
+    }
+}
+
+/// Converts the content of one Anthropic message into chat messages. Tool
+/// results become separate `tool` messages that precede the rest.
+fn to_chat_messages(role: &str, content: Content) -> Vec<Message> {
+    let mut messages = Vec::new();
+    let mut parts = Vec::new();
+    let mut tool_calls = Vec::new();
+
+    for block in content.into_blocks() {
+        match block {
+            ContentBlock::Text { text } => parts.push(ContentPart::text(&text)),
+            ContentBlock::Image { source } => parts.push(source.to_part()),
+            ContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                id,
+                kind: "function".to_string(),
+                function: FunctionCall {
+                    name,
+                    arguments: input.to_string(),
+                },
+                extra: ExtraFields::new(),
+            }),
+            ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } => {
+                let text = content.map(|c| c.text()).unwrap_or_default();
+                let text = if is_error == Some(true) {
+                    format!("Error: {}", text)
+                } else {
+                    text
+                };
+                messages.push(Message {
+                    role: "tool".to_string(),
+                    content: text,
+                    tool_call_id: Some(tool_use_id),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    if parts.is_empty() && tool_calls.is_empty() {
+        return messages;
+    }
+    let text = parts
+        .iter()
+        .filter_map(|p| p.text.as_deref())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let has_media = parts.iter().any(|p| !p.is_text());
+    messages.push(Message {
+        role: role.to_string(),
+        content: text,
+        parts: if has_media { Some(parts) } else { None },
+        tool_calls: if tool_calls.is_empty() {
+            None
+        } else {
+            Some(tool_calls)
+        },
+        ..Default::default()
+    });
+    messages
+}
+
+/// Maps Anthropic tool definitions to OpenAI function tools.
+fn to_chat_tools(tools: &Value) -> Value {
+    let tools = tools.as_array().cloned().unwrap_or_default();
+    Value::Array(
+        tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool["name"],
+                        "description": tool.get("description").cloned().unwrap_or(Value::Null),
+                        "parameters": tool.get("input_schema").cloned().unwrap_or(json!({"type": "object"})),
+                    }
+                })
+            })
+            .collect(),
+    )
+}
+
+fn to_chat_tool_choice(choice: &Value) -> Option<Value> {
+    match choice["type"].as_str()? {
+        "auto" => Some(json!("auto")),
+        "any" => Some(json!("required")),
+        "none" => Some(json!("none")),
+        "tool" => Some(json!({"type": "function", "function": {"name": choice["name"]}})),
+        _ => None,
+    }
+}
+
+impl MessagesRequest {
+    pub fn is_stream(&self) -> bool {
+        self.stream.unwrap_or(false)
+    }
+
+    /// Converts the request into a chat completion request. Parameters
+    /// without an OpenAI equivalent, like `top_k` and `metadata`, are dropped.
+    pub fn into_chat_request(self) -> ChatRequest {
+        let mut messages = Vec::new();
+        if let Some(system) = self.system {
+            messages.push(Message {
+                role: "system".to_string(),
+                content: system.text(),
+                ..Default::default()
+            });
+        }
+        for message in self.messages {
+            messages.extend(to_chat_messages(&message.role, message.content));
+        }
+
+        let mut chat_request = ChatRequest::new(self.model, messages);
+        let extra = &mut chat_request.extra;
+        extra.insert("max_tokens".to_string(), json!(self.max_tokens));
+        for (key, value) in self.extra {
+            match key.as_str() {
+                "temperature" | "top_p" | "reservoir" => {
+                    extra.insert(key, value);
+                }
+                "stop_sequences" => {
+                    extra.insert("stop".to_string(), value);
+                }
+                "tools" => {
+                    extra.insert("tools".to_string(), to_chat_tools(&value));
+                }
+                "tool_choice" => {
+                    if let Some(choice) = to_chat_tool_choice(&value) {
+                        extra.insert("tool_choice".to_string(), choice);
+                    }
+                }
+                _ => {}
+            }
+        }
+        chat_request
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AnthropicUsage {
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+}
+
+/// A response in the format of Anthropic's Messages API.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessagesResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub role: String,
+    pub model: String,
+    pub content: Vec<ContentBlock>,
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+    pub usage: AnthropicUsage,
+}
+
+fn to_stop_reason(finish_reason: &str) -> String {
+    match finish_reason {
+        "length" => "max_tokens",
+        "tool_calls" | "function_call" => "tool_use",
+        "content_filter" => "refusal",
+        _ => "end_turn",
+    }
+    .to_string()
+}
+
+impl MessagesResponse {
+    pub fn from_chat_response(response: ChatResponse, model: &str) -> Result<Self, ReservoirError> {
+        let choice = response.choices.into_iter().next().ok_or_else(|| {
+            ReservoirError::UpstreamUnavailable("The model returned no choices".to_string())
+        })?;
+
+        let mut content = Vec::new();
+        if !choice.message.content.is_empty() {
+            content.push(ContentBlock::Text {
+                text: choice.message.content,
+            });
+        }
+        for call in choice.message.tool_calls.unwrap_or_default() {
+            content.push(ContentBlock::ToolUse {
+                id: call.id,
+                name: call.function.name,
+                input: serde_json::from_str(&call.function.arguments).unwrap_or(json!({})),
+            });
+        }
+
+        Ok(MessagesResponse {
+            id: response.id.unwrap_or_default(),
+            kind: "message".to_string(),
+            role: "assistant".to_string(),
+            model: response.model.unwrap_or_else(|| model.to_string()),
+            content,
+            stop_reason: Some(to_stop_reason(
+                choice.finish_reason.as_deref().unwrap_or("stop"),
+            )),
+            stop_sequence: None,
+            usage: AnthropicUsage {
+                input_tokens: response.usage.as_ref().map_or(0, |u| u.prompt_tokens),
+                output_tokens: response.usage.as_ref().map_or(0, |u| u.completion_tokens),
+            },
+        })
+    }
+
+    /// Replays the finished response as the server sent events of a
+    /// streamed Messages API response, for clients that asked for a stream.
+    pub fn to_sse_events(&self) -> String {
+        let mut events = Vec::new();
+        let mut start = self.clone();
+        start.content = Vec::new();
+        start.stop_reason = None;
+        start.usage.output_tokens = 0;
+        events.push(("message_start", json!({"type": "message_start", "message": start})));
+
+        for (index, block) in self.content.iter().enumerate() {
+            let (empty, delta) = match block {
+                ContentBlock::Text { text } => (
+                    json!({"type": "text", "text": ""}),
+                    json!({"type": "text_delta", "text": text}),
+                ),
+                ContentBlock::ToolUse { id, name, input } => (
+                    json!({"type": "tool_use", "id": id, "name": name, "input": {}}),
+                    json!({"type": "input_json_delta", "partial_json": input.to_string()}),
+                ),
+                _ => continue,
+            };
+            events.push((
+                "content_block_start",
+                json!({"type": "content_block_start", "index": index, "content_block": empty}),
+            ));
+            events.push((
+                "content_block_delta",
+                json!({"type": "content_block_delta", "index": index, "delta": delta}),
+            ));
+            events.push((
+                "content_block_stop",
+                json!({"type": "content_block_stop", "index": index}),
+            ));
+        }
+
+        events.push((
+            "message_delta",
+            json!({
+                "type": "message_delta",
+                "delta": {"stop_reason": self.stop_reason, "stop_sequence": null},
+                "usage": {"output_tokens": self.usage.output_tokens}
+            }),
+        ));
+        events.push(("message_stop", json!({"type": "message_stop"})));
+
+        events
+            .iter()
+            .map(|(name, data)| format!("event: {}\ndata: {}\n\n", name, data))
+            .collect()
+    }
+}
+
+/// Converts a chat message part back into a content block.
+fn part_to_block(part: &ContentPart) -> Option<ContentBlock> {
+    if part.is_text() {
+        return Some(ContentBlock::Text {
+            text: part.text.clone().unwrap_or_default(),
+        });
+    }
+    if part.kind != "image_url" {
+        return None;
+    }
+    let url = part.extra.get("image_url")?.get("url")?.as_str()?;
+    let source = match url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+    {
+        Some((media_type, data)) => ImageSource::Base64 {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        },
+        None => ImageSource::Url {
+            url: url.to_string(),
+        },
+    };
+    Some(ContentBlock::Image { source })
+}
+
+fn to_blocks(message: &Message) -> Vec<ContentBlock> {
+    if message.role == "tool" {
+        return vec![ContentBlock::ToolResult {
+            tool_use_id: message.tool_call_id.clone().unwrap_or_default(),
+            content: Some(Content::Text(message.content.clone())),
+            is_error: None,
+        }];
+    }
+
+    let mut blocks: Vec<ContentBlock> = match &message.parts {
+        Some(parts) => parts.iter().filter_map(part_to_block).collect(),
+        None if message.content.is_empty() => Vec::new(),
+        None => vec![ContentBlock::Text {
+            text: message.content.clone(),
+        }],
+    };
+    for call in message.tool_calls.iter().flatten() {
+        blocks.push(ContentBlock::ToolUse {
+            id: call.id.clone(),
+            name: call.function.name.clone(),
+            input: serde_json::from_str(&call.function.arguments).unwrap_or(json!({})),
+        });
+    }
+    blocks
+}
+
+fn to_anthropic_tools(tools: &Value) -> Value {
+    let tools = tools.as_array().cloned().unwrap_or_default();
+    Value::Array(
+        tools
+            .iter()
+            .filter_map(|tool| {
+                let function = tool.get("function")?;
+                let mut converted = json!({
+                    "name": function["name"],
+                    "input_schema": function.get("parameters").cloned().unwrap_or(json!({"type": "object"})),
+                });
+                if let Some(description) = function.get("description") {
+                    converted["description"] = description.clone();
+                }
+                Some(converted)
+            })
+            .collect(),
+    )
+}
+
+fn to_anthropic_tool_choice(choice: &Value) -> Option<Value> {
+    match choice {
+        Value::String(choice) => match choice.as_str() {
+            "auto" => Some(json!({"type": "auto"})),
+            "required" => Some(json!({"type": "any"})),
+            "none" => Some(json!({"type": "none"})),
+            _ => None,
+        },
+        Value::Object(_) => Some(json!({"type": "tool", "name": choice["function"]["name"]})),
+        _ => None,
+    }
+}
+
+impl MessagesRequest {
+    /// Builds a Messages API request from a chat completion request. System
+    /// messages are moved to `system`, `tool` messages become `tool_result`
+    /// blocks and consecutive messages of the same role are merged, as the
+    /// API expects the roles to alternate.
+    pub fn from_chat_request(chat_request: &ChatRequest, default_max_tokens: u64) -> Self {
+        let system = chat_request
+            .messages
+            .iter()
+            .filter(|m| m.role == "system")
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut messages: Vec<AnthropicMessage> = Vec::new();
+        for message in chat_request.messages.iter().filter(|m| m.role != "system") {
+            let role = if message.role == "assistant" {
+                "assistant"
+            } else {
+                "user"
+            };
+            let blocks = to_blocks(message);
+            if blocks.is_empty() {
+                continue;
+            }
+            match messages.last_mut() {
+                Some(AnthropicMessage {
+                    role: last_role,
+                    content: Content::Blocks(last_blocks),
+                }) if last_role == role => last_blocks.extend(blocks),
+                _ => messages.push(AnthropicMessage {
+                    role: role.to_string(),
+                    content: Content::Blocks(blocks),
+                }),
+            }
+        }
+
+        let extra = &chat_request.extra;
+        let max_tokens = extra
+            .get("max_tokens")
+            .or_else(|| extra.get("max_completion_tokens"))
+            .and_then(Value::as_u64)
+            .unwrap_or(default_max_tokens);
+
+        let mut converted = ExtraFields::new();
+        for (key, value) in extra {
+            match key.as_str() {
+                "temperature" | "top_p" => {
+                    converted.insert(key.clone(), value.clone());
+                }
+                "stop" => {
+                    let stop = match value {
+                        Value::String(stop) => json!([stop]),
+                        other => other.clone(),
+                    };
+                    converted.insert("stop_sequences".to_string(), stop);
+                }
+                "tools" => {
+                    converted.insert("tools".to_string(), to_anthropic_tools(value));
+                }
+                "tool_choice" => {
+                    if let Some(choice) = to_anthropic_tool_choice(value) {
+                        converted.insert("tool_choice".to_string(), choice);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        MessagesRequest {
+            model: chat_request.model.clone(),
+            max_tokens,
+            system: if system.is_empty() {
+                None
+            } else {
+                Some(Content::Text(system))
+            },
+            messages,
+            stream: None,
+            extra: converted,
+        }
+    }
+}
+
+fn to_finish_reason(stop_reason: &str) -> String {
+    match stop_reason {
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        "refusal" => "content_filter",
+        _ => "stop",
+    }
+    .to_string()
+}
+
+impl MessagesResponse {
+    pub fn into_chat_response(self) -> ChatResponse {
+        let mut message = Message {
+            role: "assistant".to_string(),
+            ..Default::default()
+        };
+        let mut tool_calls = Vec::new();
+        for block in self.content {
+            match block {
+                ContentBlock::Text { text } => message.content.push_str(&text),
+                ContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                    id,
+                    kind: "function".to_string(),
+                    function: FunctionCall {
+                        name,
+                        arguments: input.to_string(),
+                    },
+                    extra: ExtraFields::new(),
+                }),
+                _ => {}
+            }
+        }
+        if !tool_calls.is_empty() {
+            message.tool_calls = Some(tool_calls);
+        }
+
+        let usage = Usage {
+            prompt_tokens: self.usage.input_tokens,
+            completion_tokens: self.usage.output_tokens,
+            total_tokens: self.usage.input_tokens + self.usage.output_tokens,
+            extra: ExtraFields::new(),
+        };
+        let choice = Choice {
+            message,
+            finish_reason: Some(to_finish_reason(
+                self.stop_reason.as_deref().unwrap_or("end_turn"),
+            )),
+            index: 0,
+            extra: ExtraFields::new(),
+        };
+        ChatResponse::new(
+            Some(self.id),
+            Some("chat.completion".to_string()),
+            Some(chrono::Utc::now().timestamp()),
+            Some(self.model),
+            Some(usage),
+            vec![choice],
+        )
+    }
+}
+
+/// The error body of the Messages API.
+pub fn to_error_body(error: &ReservoirError) -> Value {
+    let kind = match error.status().as_u16() {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        429 => "rate_limit_error",
+        529 => "overloaded_error",
+        _ => "api_error",
+    };
+    json!({
+        "type": "error",
+        "error": {"type": kind, "message": error.to_string()}
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_to_chat_request() {
+        let request: MessagesRequest = serde_json::from_str(
+            r#"{
+                "model": "gpt-4o",
+                "max_tokens": 1024,
+                "system": "Be brief",
+                "top_k": 5,
+                "stop_sequences": ["END"],
+                "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+                "messages": [
+                    {"role": "user", "content": "Weather in Cape Town?"},
+                    {"role": "assistant", "content": [
+                        {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Cape Town"}}
+                    ]},
+                    {"role": "user", "content": [
+                        {"type": "tool_result", "tool_use_id": "toolu_1", "content": "Sunny"},
+                        {"type": "text", "text": "And this?"},
+                        {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBOR"}}
+                    ]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let chat = request.into_chat_request();
+        let roles: Vec<&str> = chat.messages.iter().map(|m| m.role.as_str()).collect();
+
+        assert_eq!(roles, vec!["system", "user", "assistant", "tool", "user"]);
+        assert_eq!(chat.messages[0].content, "Be brief");
+        let call = &chat.messages[2].tool_calls.as_ref().unwrap()[0];
+        assert_eq!(call.function.arguments, r#"{"city":"Cape Town"}"#);
+        assert_eq!(chat.messages[3].tool_call_id.as_deref(), Some("toolu_1"));
+        assert_eq!(chat.messages[4].content, "And this?");
+        assert!(chat.messages[4].has_media());
+        assert_eq!(chat.extra["max_tokens"], 1024);
+        assert_eq!(chat.extra["stop"], json!(["END"]));
+        assert_eq!(chat.extra["tools"][0]["function"]["name"], "get_weather");
+        assert!(!chat.extra.contains_key("top_k"));
+    }
+
+    #[test]
+    fn test_chat_response_to_messages_response() {
+        let response: ChatResponse = serde_json::from_str(
+            r#"{
+                "id": "chatcmpl-1",
+                "model": "gpt-4o",
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+                "choices": [{
+                    "index": 0,
+                    "finish_reason": "tool_calls",
+                    "message": {
+                        "role": "assistant",
+                        "content": "Checking",
+                        "tool_calls": [{"id": "call_1", "type": "function",
+                            "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}}]
+                    }
+                }]
+            }"#,
+        )
+        .unwrap();
+        let message = MessagesResponse::from_chat_response(response, "gpt-4o").unwrap();
+        let json = serde_json::to_value(&message).unwrap();
+
+        assert_eq!(json["type"], "message");
+        assert_eq!(json["stop_reason"], "tool_use");
+        assert_eq!(json["content"][0]["text"], "Checking");
+        assert_eq!(json["content"][1]["type"], "tool_use");
+        assert_eq!(json["content"][1]["input"]["city"], "Paris");
+        assert_eq!(json["usage"]["input_tokens"], 10);
+
+        let events = message.to_sse_events();
+        assert!(events.starts_with("event: message_start\n"));
+        assert!(events.contains("\"partial_json\""));
+        assert!(events.ends_with("event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n"));
+    }
+
+    #[test]
+    fn test_chat_request_to_messages_request() {
+        let chat = ChatRequest::from_json(
+            r#"{
+                "model": "claude-sonnet-4-0",
+                "stop": "END",
+                "temperature": 0.2,
+                "tools": [{"type": "function", "function": {"name": "get_weather", "parameters": {"type": "object"}}}],
+                "messages": [
+                    {"role": "system", "content": "Be brief"},
+                    {"role": "user", "content": "Weather in Paris?"},
+                    {"role": "assistant", "content": null, "tool_calls": [{"id": "toolu_1", "type": "function",
+                        "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}}]},
+                    {"role": "tool", "tool_call_id": "toolu_1", "content": "Rain"},
+                    {"role": "user", "content": [
+                        {"type": "text", "text": "Umbrella?"},
+                        {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBOR"}}
+                    ]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let request = MessagesRequest::from_chat_request(&chat, 1024);
+        let json = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(json["system"], "Be brief");
+        assert_eq!(json["max_tokens"], 1024);
+        assert_eq!(json["stop_sequences"], json!(["END"]));
+        assert_eq!(json["tools"][0]["input_schema"]["type"], "object");
+        assert_eq!(request.messages.len(), 3);
+        assert_eq!(json["messages"][1]["content"][0]["type"], "tool_use");
+        assert_eq!(json["messages"][1]["content"][0]["input"]["city"], "Paris");
+        // The tool result and the following user message share one turn
+        assert_eq!(json["messages"][2]["role"], "user");
+        assert_eq!(json["messages"][2]["content"][0]["type"], "tool_result");
+        assert_eq!(json["messages"][2]["content"][1]["text"], "Umbrella?");
+        assert_eq!(json["messages"][2]["content"][2]["source"]["media_type"], "image/png");
+    }
+
+    #[test]
+    fn test_messages_response_to_chat_response() {
+        let response: MessagesResponse = serde_json::from_str(
+            r#"{
+                "id": "msg_1",
+                "type": "message",
+                "role": "assistant",
+                "model": "claude-sonnet-4-0",
+                "content": [{"type": "text", "text": "Let me check"},
+                    {"type": "tool_use", "id": "toolu_2", "name": "get_weather", "input": {"city": "Oslo"}}],
+                "stop_reason": "tool_use",
+                "stop_sequence": null,
+                "usage": {"input_tokens": 12, "output_tokens": 7}
+            }"#,
+        )
+        .unwrap();
+        let chat = response.into_chat_response();
+        let choice = &chat.choices[0];
+
+        assert_eq!(choice.message.content, "Let me check");
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        let calls = choice.message.tool_calls.as_ref().unwrap();
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Oslo"}"#);
+        assert_eq!(chat.usage.unwrap().total_tokens, 19);
+    }
+}
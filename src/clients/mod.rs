@@ -1 +1,6 @@
+pub mod anthropic;
 pub mod openai;
+pub mod provider;
+pub mod registry;
+pub mod retry;
+pub mod router;
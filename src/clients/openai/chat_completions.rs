@@ -1,32 +1,48 @@
 use anyhow::Error;
+use bytes::Bytes;
 use http::header;
-use tracing::{debug, error, info};
+use http_body_util::channel::Sender;
+use tracing::{debug, error, info, warn};
 
+use crate::clients::provider::Provider;
+use crate::clients::retry::parse_retry_after;
+use crate::errors::ReservoirError;
 use crate::utils::compress_system_context;
 
 use super::{
     model_info::ModelInfo,
-    types::{ChatRequest, ChatResponse},
+    stream::StreamAccumulator,
+    types::{ChatRequest, ChatResponse, Message},
 };
 
-pub async fn get_completion_message(
+/// Builds the request sent upstream. Only the model and messages are
+/// replaced, every other parameter the client sent is forwarded unchanged.
+fn prepare_request(model_info: &ModelInfo, chat_request: &ChatRequest, stream: bool) -> ChatRequest {
+    let mut chat_request = chat_request.clone();
+    chat_request.model = model_info.name.clone();
+    chat_request.messages = compress_system_context(&chat_request.messages);
+    chat_request.stream = if stream { Some(true) } else { None };
+    if !stream {
+        // Providers reject stream options on a request that is not streamed
+        chat_request.extra.remove("stream_options");
+    }
+    chat_request
+}
+
+async fn send_chat_request(
     model_info: &ModelInfo,
     chat_request: &ChatRequest,
-) -> Result<ChatResponse, Error> {
-    info!("Getting completion with model {}", model_info.name);
-    let client = reqwest::Client::new();
-
-    let context = compress_system_context(&chat_request.messages);
-    let chat_request = ChatRequest::new(model_info.name.clone(), context);
-
+    accept: &str,
+) -> Result<reqwest::Response, Error> {
     let body = match serde_json::to_string(&chat_request) {
         Ok(b) => b,
         Err(e) => {
             error!("Failed to serialize chat request model: {}", e);
-            return Err(Error::msg(format!(
+            return Err(ReservoirError::Internal(format!(
                 "Failed to serialize chat request: {}",
                 e
-            )));
+            ))
+            .into());
         }
     };
 
@@ -37,46 +53,80 @@ pub async fn get_completion_message(
         model_info.base_url.clone(),
     );
 
-    let response = client
+    let request = model_info
+        .client
         .post(model_info.base_url.clone())
         .header("Content-Type", "application/json")
-        .header("Accept", "application/json")
-        .header(header::AUTHORIZATION, format!("Bearer {}", model_info.key))
-        .body(body)
-        .send()
-        .await;
+        .header("Accept", accept);
+    let request = match model_info.provider {
+        Provider::AzureOpenAi => request.header("api-key", model_info.key.clone()),
+        _ => request.header(header::AUTHORIZATION, format!("Bearer {}", model_info.key)),
+    };
+    let request = model_info
+        .headers
+        .iter()
+        .fold(request, |request, (name, value)| request.header(name, value));
+    let response = tokio::time::timeout(model_info.timeout, request.body(body).send()).await;
 
     let response = match response {
-        Ok(resp) => resp,
-        Err(e) => {
+        Ok(Ok(resp)) => resp,
+        Err(_) => {
+            error!("LLM API did not answer within {:?}", model_info.timeout);
+            return Err(ReservoirError::UpstreamUnavailable(format!(
+                "LLM API did not answer within {} seconds",
+                model_info.timeout.as_secs()
+            ))
+            .into());
+        }
+        Ok(Err(e)) => {
             error!("Error sending request to LLM API: {}", e);
-            return Err(Error::msg(format!(
+            return Err(ReservoirError::UpstreamUnavailable(format!(
                 "Failed to send request to LLM API: {}",
                 e
-            )));
+            ))
+            .into());
         }
     };
 
     let status = response.status();
-    let response_text = match response.text().await {
-        Ok(text) => text,
-        Err(e) => {
-            error!("Error reading response text: {}", e);
-            return Err(Error::msg(format!("Failed to read response text: {}", e)));
-        }
-    };
-
     if !status.is_success() {
+        let retry_after = parse_retry_after(response.headers());
+        let response_text = response.text().await.unwrap_or_default();
         error!(
             "LLM API returned error status {}: {}",
             status, response_text
         );
-        return Err(Error::msg(format!(
-            "LLM API error {}: {}",
-            status, response_text
-        )));
+        return Err(ReservoirError::Upstream {
+            status,
+            body: response_text,
+            retry_after,
+        }
+        .into());
     }
 
+    Ok(response)
+}
+
+pub async fn get_completion_message(
+    model_info: &ModelInfo,
+    chat_request: &ChatRequest,
+) -> Result<ChatResponse, Error> {
+    info!("Getting completion with model {}", model_info.name);
+    let chat_request = prepare_request(model_info, chat_request, false);
+    let response = send_chat_request(model_info, &chat_request, "application/json").await?;
+
+    let response_text = match response.text().await {
+        Ok(text) => text,
+        Err(e) => {
+            error!("Error reading response text: {}", e);
+            return Err(ReservoirError::UpstreamUnavailable(format!(
+                "Failed to read response text: {}",
+                e
+            ))
+            .into());
+        }
+    };
+
     match ChatResponse::from_json(&response_text) {
         Ok(r) => Ok(r),
         Err(e) => {
@@ -84,10 +134,102 @@ pub async fn get_completion_message(
                 "Error parsing response JSON: {}\nRaw response: {}",
                 e, response_text
             );
-            Err(Error::msg(format!(
+            Err(ReservoirError::UpstreamUnavailable(format!(
                 "Failed to parse response JSON: {}\nRaw response: {}",
                 e, response_text
-            )))
+            ))
+            .into())
+        }
+    }
+}
+
+/// Starts a streaming completion. The returned response has already been
+/// checked for a successful status, so errors can still be reported to the
+/// client before any part of the stream has been sent.
+pub async fn get_completion_stream(
+    model_info: &ModelInfo,
+    chat_request: &ChatRequest,
+) -> Result<reqwest::Response, Error> {
+    info!("Getting streamed completion with model {}", model_info.name);
+    let chat_request = prepare_request(model_info, chat_request, true);
+    send_chat_request(model_info, &chat_request, "text/event-stream").await
+}
+
+/// Forwards the upstream event stream to the client chunk by chunk while
+/// collecting the deltas into the final assistant message.
+///
+/// If the client goes away the upstream is still read to the end so that the
+/// full answer can be stored. Should the upstream fail halfway through, the
+/// part of the answer received so far is returned.
+pub async fn forward_completion_stream(
+    mut response: reqwest::Response,
+    mut sender: Sender<Bytes>,
+) -> Message {
+    let mut accumulator = StreamAccumulator::new();
+    let mut client_connected = true;
+
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                accumulator.push(&chunk);
+                if client_connected && sender.send_data(chunk).await.is_err() {
+                    warn!("Client disconnected, reading the rest of the stream for storage");
+                    client_connected = false;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Error reading stream from LLM API: {}", e);
+                break;
+            }
+        }
+    }
+
+    if !accumulator.is_done() {
+        warn!("Stream ended without a [DONE] event");
+    }
+    accumulator.into_message()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn model_info() -> ModelInfo {
+        ModelInfo {
+            input_tokens: 128_000,
+            output_tokens: 4_096,
+            name: "gpt-4o".to_string(),
+            key: "test-key".to_string(),
+            base_url: "https://api.openai.com/v1/chat/completions".to_string(),
+            provider: Provider::OpenAiCompatible,
+            headers: Default::default(),
+            tokenizer: Default::default(),
+            capabilities: Vec::new(),
+            timeout: Duration::from_secs(120),
+            client: reqwest::Client::new(),
+            max_retries: 0,
+            fallbacks: Vec::new(),
+            larger_context: None,
         }
     }
+
+    #[test]
+    fn test_stream_options_only_sent_when_streaming() {
+        let chat_request = ChatRequest::from_json(
+            r#"{"model": "gpt-4o", "messages": [{"role": "user", "content": "Hi"}],
+                "stream": true, "stream_options": {"include_usage": true}, "temperature": 0.2}"#,
+        )
+        .unwrap();
+
+        let streamed = prepare_request(&model_info(), &chat_request, true);
+        assert!(streamed.extra.contains_key("stream_options"));
+
+        let complete = prepare_request(&model_info(), &chat_request, false);
+        assert_eq!(complete.stream, None);
+        assert!(!complete.extra.contains_key("stream_options"));
+        assert!(complete.extra.contains_key("temperature"));
+    }
 }
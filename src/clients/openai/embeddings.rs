@@ -1,80 +1,263 @@
 use anyhow::Error;
+use bytes::Bytes;
+use openssl::base64;
 use reqwest::header;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::env;
 use tracing::{error};
 
+use crate::clients::openai::types::ExtraFields;
+use crate::clients::registry::{get_model_registry, AZURE_PROVIDER, OPENAI_PROVIDER};
+use crate::clients::retry::{parse_retry_after, with_retries};
+use crate::errors::ReservoirError;
+use crate::repos::config::get_azure_config;
+
 const OPENAI_API_URL: &str = "https://api.openai.com/v1/embeddings"; // Assuming you meant the embeddings endpoint
 
-#[allow(dead_code)]
-#[derive(Deserialize, Debug)]
+/// The model used for the embeddings stored with messages. The vector index
+/// in Neo4j is built for its dimensions.
+pub const EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Embedding {
-    object: String,
-    index: i32,
-    pub embedding: Vec<f32>,
+    #[serde(default)]
+    pub object: String,
+    pub index: i32,
+    pub embedding: EmbeddingVector,
+}
+
+/// An embedding as the provider returns it, depending on the
+/// `encoding_format` of the request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    /// Little endian `f32` values, base64 encoded
+    Base64(String),
+}
+
+impl EmbeddingVector {
+    pub fn values(&self) -> Result<Vec<f32>, Error> {
+        match self {
+            EmbeddingVector::Float(values) => Ok(values.clone()),
+            EmbeddingVector::Base64(encoded) => {
+                let bytes = base64::decode_block(encoded).map_err(|e| {
+                    ReservoirError::EmbeddingFailed(format!("Invalid base64 embedding: {}", e))
+                })?;
+                Ok(bytes
+                    .chunks_exact(4)
+                    .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                    .collect())
+            }
+        }
+    }
 }
 
-#[allow(dead_code)]
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EmbeddingResponse {
-    // Define this struct according to the API response structure for embeddings
-    object: String,
+    #[serde(default)]
+    pub object: String,
     pub data: Vec<Embedding>,
+    /// Fields such as `model` and `usage` are passed on as they are
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
-#[derive(Serialize)]
-struct EmbeddingRequest {
-    input: String,
-    model: String,
+/// The `input` of an embeddings request: a text, a batch of texts, or token
+/// arrays which are forwarded without being looked at.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Text(String),
+    Batch(Vec<String>),
+    Other(Value),
 }
 
-pub async fn get_embeddings_for_text(text: &str) -> Result<Vec<Embedding>, Error> {
-    let client = reqwest::Client::new();
-    let api_key = env::var("OPENAI_API_KEY")?;
+impl EmbeddingInput {
+    /// The texts to embed, `None` for token input.
+    pub fn texts(&self) -> Option<Vec<&str>> {
+        match self {
+            EmbeddingInput::Text(text) => Some(vec![text.as_str()]),
+            EmbeddingInput::Batch(texts) => Some(texts.iter().map(String::as_str).collect()),
+            EmbeddingInput::Other(_) => None,
+        }
+    }
+}
 
-    // Set up the request payload
-    let request_body = EmbeddingRequest {
-        input: text.to_string(),
-        model: "text-embedding-ada-002".to_string(), // Replace with the appropriate model name
-    };
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingRequest {
+    pub input: EmbeddingInput,
+    #[serde(default = "embedding_model")]
+    pub model: String,
+    /// Parameters such as `dimensions` and `encoding_format`
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+fn embedding_model() -> String {
+    EMBEDDING_MODEL.to_string()
+}
+
+impl EmbeddingRequest {
+    pub fn new(input: EmbeddingInput) -> Self {
+        EmbeddingRequest {
+            input,
+            model: embedding_model(),
+            extra: ExtraFields::new(),
+        }
+    }
+}
 
-    // Set up the request headers
+/// Creates embeddings on the Azure deployment configured for them, or on
+/// OpenAI otherwise, and parses the answer.
+pub async fn create_embeddings(request: &EmbeddingRequest) -> Result<EmbeddingResponse, Error> {
+    let body = send_embeddings_request(request).await?;
+    Ok(serde_json::from_slice(&body).map_err(|e| {
+        ReservoirError::EmbeddingFailed(format!("Invalid embeddings response: {}", e))
+    })?)
+}
+
+/// Sends an embeddings request and returns the body of the provider's
+/// answer as it is. Rate limits and server errors are retried as configured
+/// for the provider.
+pub async fn send_embeddings_request(request: &EmbeddingRequest) -> Result<Bytes, Error> {
     let mut headers = header::HeaderMap::new();
     headers.insert(
         header::CONTENT_TYPE,
         header::HeaderValue::from_static("application/json"),
     );
-    headers.insert(
-        header::AUTHORIZATION,
-        header::HeaderValue::from_str(&format!("Bearer {}", api_key))
-            .expect("Invalid API key format"),
-    );
+    let invalid_key = |_| ReservoirError::EmbeddingFailed("Invalid API key format".to_string());
+    let azure = get_azure_config()
+        .and_then(|azure| Some((azure, azure.embedding_deployment.as_deref()?)));
+    let (provider, url) = match azure {
+        Some((azure, deployment)) => {
+            headers.insert(
+                "api-key",
+                header::HeaderValue::from_str(&azure.api_key()).map_err(invalid_key)?,
+            );
+            (AZURE_PROVIDER, azure.deployment_url(deployment, "embeddings"))
+        }
+        None => {
+            let api_key = env::var("OPENAI_API_KEY").map_err(|_| {
+                ReservoirError::EmbeddingFailed("OPENAI_API_KEY is not set, cannot create embeddings".to_string())
+            })?;
+            headers.insert(
+                header::AUTHORIZATION,
+                header::HeaderValue::from_str(&format!("Bearer {}", api_key)).map_err(invalid_key)?,
+            );
+            (OPENAI_PROVIDER, OPENAI_API_URL.to_string())
+        }
+    };
+    let (client, max_retries) = get_model_registry().provider_client(provider).ok_or_else(|| {
+        ReservoirError::EmbeddingFailed(format!("The provider '{}' is not configured", provider))
+    })?;
+
+    let attempt = || send_once(&client, &url, &headers, request);
+    with_retries(max_retries, &request.model, "embeddings", attempt)
+        .await
+        .map_err(|e| match e.downcast::<ReservoirError>() {
+            Ok(ReservoirError::Upstream { status, body, .. }) => ReservoirError::EmbeddingFailed(format!(
+                "Failed to get embeddings, status {}: {}",
+                status, body
+            ))
+            .into(),
+            Ok(ReservoirError::UpstreamUnavailable(message)) => {
+                ReservoirError::EmbeddingFailed(message).into()
+            }
+            Ok(e) => e.into(),
+            Err(e) => e,
+        })
+}
 
-    // Send the request and handle response
+async fn send_once(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &header::HeaderMap,
+    request: &EmbeddingRequest,
+) -> Result<Bytes, Error> {
     let response = client
-        .post(OPENAI_API_URL)
-        .headers(headers)
-        .json(&request_body)
+        .post(url)
+        .headers(headers.clone())
+        .json(request)
         .send()
-        .await;
-
-    match response {
-        Ok(res) => {
-            if res.status().is_success() {
-                let embeddings: EmbeddingResponse =
-                    res.json().await?;
-                Ok(embeddings.data)
-            } else {
-                error!("Error: Received non-success status code {}", res.status());
-                let error_response: serde_json::Value =
-                    res.json().await.expect("Failed to parse error response");         
-                error!("Error response: {:?}", error_response);
-                panic!("Failed to get embeddings");
-            }
-        }
-        Err(e) => {
+        .await
+        .map_err(|e| {
             error!("Error sending request: {}", e);
-            panic!("Failed to send request to OpenAI API");
+            ReservoirError::UpstreamUnavailable(format!("Failed to send embeddings request: {}", e))
+        })?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let retry_after = parse_retry_after(response.headers());
+        let body = response.text().await.unwrap_or_default();
+        error!("Embeddings API returned error status {}: {}", status, body);
+        return Err(ReservoirError::Upstream {
+            status,
+            body,
+            retry_after,
         }
+        .into());
+    }
+    Ok(response.bytes().await.map_err(|e| {
+        ReservoirError::UpstreamUnavailable(format!("Could not read embeddings response: {}", e))
+    })?)
+}
+
+pub async fn get_embeddings_for_text(text: &str) -> Result<Vec<Embedding>, Error> {
+    let request = EmbeddingRequest::new(EmbeddingInput::Text(text.to_string()));
+    Ok(create_embeddings(&request).await?.data)
+}
+
+/// Returns the embedding of a single text.
+pub async fn get_embedding_for_text(text: &str) -> Result<Vec<f32>, Error> {
+    get_embeddings_for_text(text)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::from(ReservoirError::EmbeddingFailed("No embedding returned".to_string())))?
+        .embedding
+        .values()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_request_input_forms() {
+        let single: EmbeddingRequest = serde_json::from_str(r#"{"input": "hello"}"#).unwrap();
+        let batch: EmbeddingRequest = serde_json::from_str(
+            r#"{"input": ["a", "b"], "model": "text-embedding-3-small", "dimensions": 256}"#,
+        )
+        .unwrap();
+        let tokens: EmbeddingRequest = serde_json::from_str(r#"{"input": [[1, 2, 3]]}"#).unwrap();
+
+        assert_eq!(single.model, EMBEDDING_MODEL);
+        assert_eq!(single.input.texts(), Some(vec!["hello"]));
+        assert_eq!(batch.input.texts(), Some(vec!["a", "b"]));
+        assert_eq!(batch.extra["dimensions"], 256);
+        assert_eq!(tokens.input.texts(), None);
+        assert_eq!(
+            serde_json::to_value(&tokens).unwrap()["input"],
+            serde_json::json!([[1, 2, 3]])
+        );
+    }
+
+    #[test]
+    fn test_base64_embedding_response() {
+        // [1.0, -2.5] as little endian f32, what `encoding_format: "base64"` returns
+        let response: EmbeddingResponse = serde_json::from_str(
+            r#"{"object": "list", "model": "text-embedding-ada-002",
+                "data": [{"object": "embedding", "index": 0, "embedding": "AACAPwAAIMA="}]}"#,
+        )
+        .unwrap();
+        let float: EmbeddingResponse = serde_json::from_str(
+            r#"{"data": [{"index": 0, "embedding": [1.0, -2.5]}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(response.data[0].embedding.values().unwrap(), vec![1.0, -2.5]);
+        assert_eq!(float.data[0].embedding.values().unwrap(), vec![1.0, -2.5]);
     }
 }
@@ -1,4 +1,6 @@
 pub mod chat_completions;
 pub mod embeddings;
 pub mod model_info;
+pub mod models;
+pub mod stream;
 pub mod types;
@@ -1,125 +1,118 @@
-use std::env;
-
-const RSV_OPENAI_BASE_URL: &str = "RSV_OPENAI_BASE_URL";
-const RSV_OLLAMA_BASE_URL: &str = "RSV_OLLAMA_BASE_URL";
-const RSV_MISTRAL_BASE_URL: &str = "RSV_MISTRAL_BASE_URL";
-
-fn openai_base_url() -> String {
-    env::var(RSV_OPENAI_BASE_URL)
-        .unwrap_or_else(|_| "https://api.openai.com/v1/chat/completions".to_string())
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::clients::provider::Provider;
+use crate::utils::Tokenizer;
+
+/// An upstream that can be asked which models it serves.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModelSource {
+    /// Lists its models at `/v1/models`
+    OpenAiCompatible {
+        owned_by: String,
+        url: String,
+        key: String,
+    },
+    /// Lists its models at `/api/tags`
+    Ollama { url: String },
 }
 
-fn ollama_base_url() -> String {
-    env::var(RSV_OLLAMA_BASE_URL)
-        .unwrap_or_else(|_| "http://localhost:11434/v1/chat/completions".to_string())
-}
-fn mistral_base_url() -> String {
-    env::var(RSV_MISTRAL_BASE_URL)
-        .unwrap_or_else(|_| "https://api.mistral.ai/v1/chat/completions".to_string())
+impl ModelSource {
+    pub fn url(&self) -> &str {
+        match self {
+            ModelSource::OpenAiCompatible { url, .. } | ModelSource::Ollama { url } => url,
+        }
+    }
 }
 
-fn gemini_base_url() -> String {
-    "https://generativelanguage.googleapis.com/v1beta/openai/chat/completions".to_string()
+/// Turns a chat completions URL into the models URL of the same API.
+pub fn models_url(chat_url: &str) -> String {
+    let base = chat_url
+        .strip_suffix("/chat/completions")
+        .unwrap_or(chat_url);
+    format!("{}/models", base.trim_end_matches('/'))
 }
 
+/// Everything needed to send a request to a model, as resolved from the
+/// model registry.
+#[derive(Debug, Clone)]
 pub struct ModelInfo {
     /// The maximum number of input tokens for the model
     pub input_tokens: usize,
-    #[allow(dead_code)]
     pub output_tokens: usize,
 
-    /// Name of the model
+    /// Name of the model as sent to the provider
     pub name: String,
     pub key: String,
 
     /// Base URL for the model API
     pub base_url: String,
+    pub provider: Provider,
+    /// Extra headers configured for the provider
+    pub headers: HashMap<String, String>,
+    pub tokenizer: Tokenizer,
+    pub capabilities: Vec<String>,
+    /// How long to wait for the provider to start answering
+    pub timeout: Duration,
+    /// The client of the provider, built with its timeouts
+    pub client: reqwest::Client,
+    pub max_retries: u32,
+    /// Models to try, in order, when this one keeps failing
+    pub fallbacks: Vec<String>,
+    /// Model to switch to when a prompt does not fit this one
+    pub larger_context: Option<String>,
 }
 
 impl ModelInfo {
-    pub fn new(name: String) -> Self {
-        match name.as_str() {
-            "gpt-4.1" => Self::new_gpt_4_1(),
-            "gpt-4o" => Self::new_gpt_4o(),
-            "gpt-4o-mini" => Self::new_gpt_4o_mini(),
-            "llama3.2" => Self::new_llama3_2(),
-            "mistral-large-2402" => Self::new_mistral_large_2402(),
-            "gemini-2.0-flash" => Self::new_gemini_2_0_flash(),
-            _ => Self::default(name),
-        }
-    }
-
-    pub fn new_gpt_4_1() -> Self {
-        ModelInfo {
-            input_tokens: 128_000,
-            output_tokens: 4_096,
-            name: "gpt-4.1".to_string(),
-            key: env::var("OPENAI_API_KEY").unwrap_or_default(),
-            base_url: openai_base_url(),
-        }
-    }
-
-    pub fn new_gpt_4o() -> Self {
-        ModelInfo {
-            input_tokens: 128_000,
-            output_tokens: 4_096,
-            name: "gpt-4o".to_string(),
-            key: env::var("OPENAI_API_KEY").unwrap_or_default(),
-            base_url: openai_base_url(),
-        }
+    /// True if the model is declared with the capability, or declares none.
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.is_empty() || self.capabilities.iter().any(|c| c == capability)
     }
 
-    fn new_gpt_4o_mini() -> ModelInfo {
-        ModelInfo {
-            input_tokens: 48_000,
-            output_tokens: 4_096,
-            name: "gpt-4o-mini".to_string(),
-            key: env::var("OPENAI_API_KEY").unwrap_or_default(),
-            base_url: openai_base_url(),
-        }
-    }
-
-    fn new_llama3_2() -> ModelInfo {
-        ModelInfo {
-            input_tokens: 128_000,
-            output_tokens: 2048,
-            name: "llama3.2".to_string(),
-            key: "".to_string(),
-            base_url: ollama_base_url(),
-        }
-    }
+}
 
-    fn new_mistral_large_2402() -> ModelInfo {
-        ModelInfo {
-            input_tokens: 128_000,
-            output_tokens: 2048,
-            name: "mistral-large-2402".to_string(),
-            key: env::var("MISTRAL_API_KEY").unwrap_or_default(),
-            base_url: mistral_base_url(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_models_url() {
+        assert_eq!(
+            models_url("https://api.openai.com/v1/chat/completions"),
+            "https://api.openai.com/v1/models"
+        );
+        assert_eq!(
+            models_url("https://generativelanguage.googleapis.com/v1beta/openai/chat/completions"),
+            "https://generativelanguage.googleapis.com/v1beta/openai/models"
+        );
     }
 
-    fn new_gemini_2_0_flash() -> ModelInfo {
+    fn model(name: &str) -> ModelInfo {
         ModelInfo {
             input_tokens: 128_000,
-            output_tokens: 2048,
-            name: "gemini-2.0-flash".to_string(),
-            key: env::var("GEMINI_API_KEY").unwrap_or_default(),
-            base_url: gemini_base_url(),
+            output_tokens: 4_096,
+            name: name.to_string(),
+            key: "openai-key".to_string(),
+            base_url: "https://api.openai.com/v1/chat/completions".to_string(),
+            provider: Provider::OpenAiCompatible,
+            headers: HashMap::new(),
+            tokenizer: Tokenizer::default(),
+            capabilities: Vec::new(),
+            timeout: Duration::from_secs(120),
+            client: reqwest::Client::new(),
+            max_retries: 0,
+            fallbacks: Vec::new(),
+            larger_context: None,
         }
     }
 
-    fn default(name: String) -> ModelInfo {
-        let ollama_base_url_from_env =
-            env::var("OLLAMA_BASE_URL").unwrap_or("http://localhost:11434".to_string());
-        let base_url = format!("{}/v1/chat/completions", ollama_base_url_from_env);
+    #[test]
+    fn test_supports() {
+        let mut info = model("gpt-4o");
+        assert!(info.supports("vision"));
 
-        ModelInfo {
-            input_tokens: 128_000,
-            output_tokens: 2048,
-            name,
-            key: env::var("OLLAMA_API_KEY").unwrap_or_default(),
-            base_url,
-        }
+        info.capabilities = vec!["tools".to_string()];
+        assert!(info.supports("tools"));
+        assert!(!info.supports("vision"));
     }
 }
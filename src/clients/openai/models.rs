@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::clients::openai::model_info::ModelSource;
+use crate::errors::ReservoirError;
+
+/// How long to wait for an upstream to list its models. A provider that is
+/// down must not hold up the whole list.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelObject {
+    pub id: String,
+    #[serde(default = "model_object")]
+    pub object: String,
+    #[serde(default)]
+    pub created: i64,
+    #[serde(default)]
+    pub owned_by: String,
+}
+
+fn model_object() -> String {
+    "model".to_string()
+}
+
+impl ModelObject {
+    pub fn new(id: &str, owned_by: &str) -> Self {
+        ModelObject {
+            id: id.to_string(),
+            object: model_object(),
+            created: 0,
+            owned_by: owned_by.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelList {
+    pub object: String,
+    pub data: Vec<ModelObject>,
+}
+
+impl ModelList {
+    /// Builds a list, keeping only the first model with a given id.
+    pub fn new(models: Vec<ModelObject>) -> Self {
+        let mut data: Vec<ModelObject> = Vec::new();
+        for model in models {
+            if !data.iter().any(|m| m.id == model.id) {
+                data.push(model);
+            }
+        }
+        ModelList {
+            object: "list".to_string(),
+            data,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaTags {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaModel {
+    name: String,
+}
+
+impl From<OllamaTags> for Vec<ModelObject> {
+    fn from(tags: OllamaTags) -> Self {
+        tags.models
+            .iter()
+            .map(|m| {
+                // Ollama resolves "llama3.2" to "llama3.2:latest", list the short name
+                let id = m.name.strip_suffix(":latest").unwrap_or(&m.name);
+                ModelObject::new(id, "ollama")
+            })
+            .collect()
+    }
+}
+
+/// Asks an upstream which models it serves.
+pub async fn list_models(source: &ModelSource) -> Result<Vec<ModelObject>, Error> {
+    let client = reqwest::Client::builder()
+        .timeout(DISCOVERY_TIMEOUT)
+        .build()?;
+    let unavailable = |e: reqwest::Error| ReservoirError::UpstreamUnavailable(e.to_string());
+
+    match source {
+        ModelSource::OpenAiCompatible { owned_by, url, key } => {
+            let list: ModelList = client
+                .get(url)
+                .bearer_auth(key)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(unavailable)?
+                .json()
+                .await
+                .map_err(unavailable)?;
+            Ok(list
+                .data
+                .into_iter()
+                .map(|mut m| {
+                    if m.owned_by.is_empty() {
+                        m.owned_by = owned_by.clone();
+                    }
+                    m
+                })
+                .collect())
+        }
+        ModelSource::Ollama { url } => {
+            let tags: OllamaTags = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(unavailable)?
+                .json()
+                .await
+                .map_err(unavailable)?;
+            Ok(tags.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ollama_tags_to_models() {
+        let tags: OllamaTags = serde_json::from_str(
+            r#"{"models": [
+                {"name": "llama3.2:latest", "model": "llama3.2:latest", "size": 2019393189},
+                {"name": "qwen2.5-coder:7b", "model": "qwen2.5-coder:7b"}
+            ]}"#,
+        )
+        .unwrap();
+        let models: Vec<ModelObject> = tags.into();
+
+        assert_eq!(models[0].id, "llama3.2");
+        assert_eq!(models[1].id, "qwen2.5-coder:7b");
+        assert_eq!(models[1].owned_by, "ollama");
+    }
+
+    #[test]
+    fn test_model_list_keeps_first_of_each_id() {
+        let list = ModelList::new(vec![
+            ModelObject::new("gpt-4o", "openai"),
+            ModelObject::new("llama3.2", "ollama"),
+            ModelObject::new("gpt-4o", "system"),
+        ]);
+        let json = serde_json::to_value(&list).unwrap();
+
+        assert_eq!(list.data.len(), 2);
+        assert_eq!(list.data[0].owned_by, "openai");
+        assert_eq!(json["object"], "list");
+        assert_eq!(json["data"][0]["object"], "model");
+    }
+}
@@ -0,0 +1,171 @@
+use tracing::debug;
+
+use super::types::{ChatCompletionChunk, Message, ToolCall};
+
+/// Incrementally parses an OpenAI style `text/event-stream` body and
+/// puts the streamed deltas back together into a single assistant message.
+#[derive(Debug, Default)]
+pub struct StreamAccumulator {
+    buffer: Vec<u8>,
+    role: Option<String>,
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    done: bool,
+}
+
+impl StreamAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed raw bytes as they arrive from upstream. Chunks do not have to
+    /// line up with event boundaries, incomplete lines are kept until the
+    /// rest of them arrives.
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            self.process_line(&line);
+        }
+    }
+
+    fn process_line(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let Some(data) = line.trim().strip_prefix("data:") else {
+            return;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            self.done = true;
+            return;
+        }
+
+        match serde_json::from_str::<ChatCompletionChunk>(data) {
+            Ok(chunk) => {
+                // Only the first choice is stored, same as non streaming requests
+                for choice in chunk.choices.iter().filter(|c| c.index == 0) {
+                    if let Some(role) = &choice.delta.role {
+                        self.role = Some(role.clone());
+                    }
+                    if let Some(content) = &choice.delta.content {
+                        self.content.push_str(content);
+                    }
+                    for delta in choice.delta.tool_calls.iter().flatten() {
+                        while self.tool_calls.len() <= delta.index {
+                            self.tool_calls.push(ToolCall {
+                                kind: "function".to_string(),
+                                ..Default::default()
+                            });
+                        }
+                        let call = &mut self.tool_calls[delta.index];
+                        if let Some(id) = &delta.id {
+                            call.id = id.clone();
+                        }
+                        if let Some(kind) = &delta.kind {
+                            call.kind = kind.clone();
+                        }
+                        if let Some(function) = &delta.function {
+                            if let Some(name) = &function.name {
+                                call.function.name.push_str(name);
+                            }
+                            if let Some(arguments) = &function.arguments {
+                                call.function.arguments.push_str(arguments);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => debug!("Skipping unparseable stream event: {} ({})", data, e),
+        }
+    }
+
+    /// True once the `[DONE]` sentinel has been seen.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn into_message(self) -> Message {
+        Message {
+            role: self.role.unwrap_or_else(|| "assistant".to_string()),
+            content: self.content,
+            tool_calls: if self.tool_calls.is_empty() {
+                None
+            } else {
+                Some(self.tool_calls)
+            },
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(content: &str) -> String {
+        format!(
+            "data: {{\"id\":\"1\",\"object\":\"chat.completion.chunk\",\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{}\"}},\"finish_reason\":null}}]}}\n\n",
+            content
+        )
+    }
+
+    #[test]
+    fn test_accumulates_deltas() {
+        let mut acc = StreamAccumulator::new();
+        acc.push(b"data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\"},\"finish_reason\":null}]}\n\n");
+        acc.push(event("Hello").as_bytes());
+        acc.push(event(", world").as_bytes());
+        acc.push(b"data: [DONE]\n\n");
+
+        assert!(acc.is_done());
+        let message = acc.into_message();
+        assert_eq!(message.role, "assistant");
+        assert_eq!(message.content, "Hello, world");
+    }
+
+    #[test]
+    fn test_events_split_across_chunks() {
+        let mut acc = StreamAccumulator::new();
+        let stream = format!("{}{}data: [DONE]\n\n", event("foo"), event("bar"));
+        for chunk in stream.as_bytes().chunks(7) {
+            acc.push(chunk);
+        }
+
+        assert!(acc.is_done());
+        assert_eq!(acc.into_message().content, "foobar");
+    }
+
+    #[test]
+    fn test_accumulates_tool_calls() {
+        let mut acc = StreamAccumulator::new();
+        acc.push(br#"data: {"choices":[{"index":0,"delta":{"role":"assistant","content":null,"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}]},"finish_reason":null}]}"#);
+        acc.push(b"\n\n");
+        acc.push(br#"data: {"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":"}}]},"finish_reason":null}]}"#);
+        acc.push(b"\n\n");
+        acc.push(br#"data: {"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"Paris\"}"}}]},"finish_reason":"tool_calls"}]}"#);
+        acc.push(b"\n\n");
+        acc.push(b"data: [DONE]\n\n");
+
+        let message = acc.into_message();
+        let calls = message.tool_calls.unwrap();
+        assert_eq!(message.content, "");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].kind, "function");
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+    }
+
+    #[test]
+    fn test_ignores_other_choices_and_comments() {
+        let mut acc = StreamAccumulator::new();
+        acc.push(b": keep-alive\n\n");
+        acc.push(event("kept").as_bytes());
+        acc.push(b"data: {\"choices\":[{\"index\":1,\"delta\":{\"content\":\"dropped\"},\"finish_reason\":null}]}\n\n");
+
+        assert!(!acc.is_done());
+        let message = acc.into_message();
+        assert_eq!(message.role, "assistant");
+        assert_eq!(message.content, "kept");
+    }
+}
@@ -1,12 +1,199 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 use crate::models::message_node::MessageNode;
+use crate::utils::sha256_hex;
 
+/// Fields that Reservoir does not model itself. They are kept as they are
+/// and sent along unchanged, so nothing the client or provider added is lost.
+pub type ExtraFields = Map<String, Value>;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(from = "WireMessage", into = "WireMessage")]
 pub struct Message {
     pub role: String,
+    /// The text of the message. When the content was sent as an array of
+    /// parts this holds the text parts joined together.
     pub content: String,
+    /// The original content parts when the content was sent as an array,
+    /// for example to include images or audio
+    pub parts: Option<Vec<ContentPart>>,
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Set on `role: "tool"` messages to the id of the call they answer
+    pub tool_call_id: Option<String>,
+    pub extra: ExtraFields,
+}
+
+impl Message {
+    /// The text used for embeddings and search. Tool calls without any
+    /// content are described by the functions they call.
+    pub fn searchable_text(&self) -> String {
+        match &self.tool_calls {
+            Some(calls) if self.content.is_empty() => calls
+                .iter()
+                .map(ToolCall::describe)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => self.content.clone(),
+        }
+    }
+
+    /// True if the message carries anything other than text, such as images.
+    pub fn has_media(&self) -> bool {
+        self.parts
+            .as_ref()
+            .is_some_and(|parts| parts.iter().any(|p| !p.is_text()))
+    }
+
+    /// Identifies the message by its content and everything that came before
+    /// it in the conversation, given as the chain hash of the previous
+    /// message. The same message at another point of a conversation gets a
+    /// different hash. Fields the client may add or drop when it sends the
+    /// history back, such as `name` or `refusal`, are left out.
+    pub fn chain_hash(&self, parent: Option<&str>) -> String {
+        let content = match self.parts.as_ref().filter(|_| self.has_media()) {
+            Some(parts) => serde_json::to_string(parts).unwrap_or_default(),
+            None => self.content.clone(),
+        };
+        let calls: Vec<String> = self
+            .tool_calls
+            .iter()
+            .flatten()
+            .map(|call| format!("{}:{}:{}", call.id, call.function.name, call.function.arguments))
+            .collect();
+        let canonical = serde_json::json!([
+            parent,
+            self.role,
+            content,
+            calls,
+            self.tool_call_id,
+        ]);
+        sha256_hex(canonical.to_string().as_bytes())
+    }
+
+    /// Appends text to the message, keeping the content parts in sync.
+    pub fn push_text(&mut self, text: &str) {
+        self.content.push_str(text);
+        if let Some(parts) = self.parts.as_mut() {
+            parts.push(ContentPart::text(text));
+        }
+    }
+}
+
+/// A single element of an array valued `content`. Only text is interpreted,
+/// everything else (`image_url`, `input_audio`, `file`, ...) is kept as is.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+impl ContentPart {
+    pub fn text(text: &str) -> Self {
+        ContentPart {
+            kind: "text".to_string(),
+            text: Some(text.to_string()),
+            extra: ExtraFields::new(),
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.kind == "text"
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum WireContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+/// The message as it appears on the wire, where `content` may be a string,
+/// an array of parts or `null`.
+#[derive(Debug, Serialize, Deserialize)]
+struct WireMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<WireContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+    #[serde(flatten)]
+    extra: ExtraFields,
+}
+
+impl From<WireMessage> for Message {
+    fn from(wire: WireMessage) -> Self {
+        let (content, parts) = match wire.content {
+            Some(WireContent::Text(text)) => (text, None),
+            Some(WireContent::Parts(parts)) => {
+                let text = parts
+                    .iter()
+                    .filter_map(|p| p.text.as_deref())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                (text, Some(parts))
+            }
+            None => (String::new(), None),
+        };
+        Message {
+            role: wire.role,
+            content,
+            parts,
+            tool_calls: wire.tool_calls,
+            tool_call_id: wire.tool_call_id,
+            extra: wire.extra,
+        }
+    }
+}
+
+impl From<Message> for WireMessage {
+    fn from(message: Message) -> Self {
+        let content = match message.parts {
+            Some(parts) => Some(WireContent::Parts(parts)),
+            None if message.content.is_empty() && message.tool_calls.is_some() => None,
+            None => Some(WireContent::Text(message.content)),
+        };
+        WireMessage {
+            role: message.role,
+            content,
+            tool_calls: message.tool_calls,
+            tool_call_id: message.tool_call_id,
+            extra: message.extra,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+impl ToolCall {
+    pub fn describe(&self) -> String {
+        format!(
+            "Called tool `{}` with arguments: {}",
+            self.function.name, self.function.arguments
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments, exactly as the model produced them
+    pub arguments: String,
 }
 
 
@@ -18,6 +205,9 @@ pub struct ErrorResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorDetail {
     pub message: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    pub code: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,25 +215,43 @@ pub struct Usage {
     pub prompt_tokens: i64,
     pub completion_tokens: i64,
     pub total_tokens: i64,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Choice {
    pub message: Message,
-   pub finish_reason: String,
+   pub finish_reason: Option<String>,
    pub index: u64,
+   #[serde(flatten)]
+   pub extra: ExtraFields,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatRequest {
     pub model: String,
     pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    /// Every other request parameter (`temperature`, `max_tokens`, `tools`, ...)
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[allow(dead_code)]
 impl ChatRequest {
     pub fn new(model: String, messages: Vec<Message>) -> Self {
-        ChatRequest { model, messages }
+        ChatRequest {
+            model,
+            messages,
+            stream: None,
+            extra: ExtraFields::new(),
+        }
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.stream.unwrap_or(false)
     }
 
     pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
@@ -71,15 +279,17 @@ pub fn enrich_chat_request(
     enrichment_block.push(Message {
         role: "system".to_string(),
         content: semantic_prompt.to_string(),
+        ..Default::default()
     });
-    enrichment_block.extend(similar_messages.iter().map(MessageNode::to_message));
+    enrichment_block.extend(similar_messages.iter().map(MessageNode::to_context_message));
     enrichment_block.push(Message {
         role: "system".to_string(),
         content: recent_prompt.to_string(),
+        ..Default::default()
     });
-    enrichment_block.extend(last_messages.iter().map(MessageNode::to_message));
+    enrichment_block.extend(last_messages.iter().map(MessageNode::to_context_message));
 
-    enrichment_block.retain(|m| !m.content.is_empty());
+    enrichment_block.retain(|m| !m.content.is_empty() || m.has_media());
 
     let insert_index = if chat_request
         .messages
@@ -100,12 +310,20 @@ pub fn enrich_chat_request(
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub object: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub created: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub usage: Option<Usage>,
     pub choices: Vec<Choice>,
+    /// Provider specific fields such as `system_fingerprint`
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[allow(dead_code)]
@@ -125,6 +343,7 @@ impl ChatResponse {
             model,
             usage,
             choices,
+            extra: ExtraFields::new(),
         }
     }
 
@@ -133,6 +352,48 @@ impl ChatResponse {
     }
 }
 
+/// The partial message carried by a single streamed chunk.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Delta {
+    pub role: Option<String>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A fragment of a tool call. The arguments arrive spread over many chunks
+/// and are joined by `index`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkChoice {
+    pub index: u64,
+    pub delta: Delta,
+    pub finish_reason: Option<String>,
+}
+
+/// A single `chat.completion.chunk` event from a streaming response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub id: Option<String>,
+    pub object: Option<String>,
+    pub created: Option<i64>,
+    pub model: Option<String>,
+    pub choices: Vec<ChunkChoice>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -141,6 +402,7 @@ mod tests {
     // Helper function to create a dummy MessageNode
     fn create_dummy_node(role: &str, content: &str, timestamp: i64) -> MessageNode {
         MessageNode {
+            id: format!("message-{}", timestamp),
             trace_id: format!("trace-{}", timestamp),
             partition: "test".to_string(),
             instance: "test_instance".to_string(),
@@ -148,7 +410,14 @@ mod tests {
             content: Some(content.to_string()),
             embedding: vec![0.0], // Dummy embedding
             url: None,
+            parts: None,
             timestamp,
+            tool_calls: None,
+            tool_call_id: None,
+            model: None,
+            switched_from: None,
+            chain_hash: None,
+            parent_hash: None,
         }
     }
 
@@ -157,6 +426,7 @@ mod tests {
         Message {
             role: role.to_string(),
             content: content.to_string(),
+            ..Default::default()
         }
     }
 
@@ -173,6 +443,8 @@ mod tests {
         let mut chat_request = ChatRequest {
             model: "test-model".to_string(),
             messages: vec![create_dummy_message("user", "current user message")],
+            stream: None,
+            extra: ExtraFields::new(),
         };
 
         let chat_request = enrich_chat_request(similar, last, &mut chat_request);
@@ -201,6 +473,8 @@ mod tests {
                 create_dummy_message("system", "initial system prompt"),
                 create_dummy_message("user", "current user message"),
             ],
+            stream: None,
+            extra: ExtraFields::new(),
         };
 
         let chat_request = enrich_chat_request(similar, last, &mut chat_request);
@@ -232,6 +506,8 @@ mod tests {
                 create_dummy_message("user", "already exists"), // Existing message
                 create_dummy_message("user", "current user message"),
             ],
+            stream: None,
+            extra: ExtraFields::new(),
         };
 
         let chat_request = enrich_chat_request(similar, last, &mut chat_request);
@@ -252,6 +528,8 @@ mod tests {
         let mut chat_request = ChatRequest {
             model: "test-model".to_string(),
             messages: vec![create_dummy_message("user", "current user message")],
+            stream: None,
+            extra: ExtraFields::new(),
         };
 
         let original_len = chat_request.messages.len();
@@ -262,4 +540,147 @@ mod tests {
         assert_eq!(chat_request.messages[1].role, "system"); // Recent prompt
         assert_eq!(chat_request.messages[2].role, "user"); // Original user message
     }
+
+    #[test]
+    fn test_request_parameters_are_kept() {
+        let json = r#"{
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "hi", "name": "divan"}],
+            "temperature": 0.2,
+            "max_tokens": 64,
+            "response_format": {"type": "json_object"},
+            "stop": ["\n"]
+        }"#;
+        let chat_request = ChatRequest::from_json(json).unwrap();
+        let value = serde_json::to_value(&chat_request).unwrap();
+
+        assert_eq!(value["temperature"], 0.2);
+        assert_eq!(value["max_tokens"], 64);
+        assert_eq!(value["response_format"]["type"], "json_object");
+        assert_eq!(value["stop"][0], "\n");
+        assert_eq!(value["messages"][0]["name"], "divan");
+        assert!(value.get("stream").is_none());
+    }
+
+    #[test]
+    fn test_response_fields_are_kept() {
+        let json = r#"{
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "gpt-4o",
+            "system_fingerprint": "fp_1",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "hello", "refusal": null},
+                "logprobs": null,
+                "finish_reason": "stop"
+            }],
+            "usage": {
+                "prompt_tokens": 1,
+                "completion_tokens": 1,
+                "total_tokens": 2,
+                "prompt_tokens_details": {"cached_tokens": 0}
+            }
+        }"#;
+        let chat_response = ChatResponse::from_json(json).unwrap();
+        let value = serde_json::to_value(&chat_response).unwrap();
+        let original: serde_json::Value = serde_json::from_str(json).unwrap();
+
+        assert_eq!(value, original);
+    }
+
+    #[test]
+    fn test_tool_call_messages() {
+        let json = r#"{
+            "model": "gpt-4o",
+            "tools": [{"type": "function", "function": {"name": "get_weather", "parameters": {}}}],
+            "messages": [
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": null, "tool_calls": [
+                    {"id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}}
+                ]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "18C and sunny"}
+            ]
+        }"#;
+        let chat_request = ChatRequest::from_json(json).unwrap();
+        let assistant = &chat_request.messages[1];
+        let tool = &chat_request.messages[2];
+
+        assert_eq!(assistant.content, "");
+        assert_eq!(assistant.searchable_text(), r#"Called tool `get_weather` with arguments: {"city":"Paris"}"#);
+        assert_eq!(tool.tool_call_id.as_deref(), Some("call_1"));
+
+        let value = serde_json::to_value(&chat_request).unwrap();
+        assert_eq!(value["tools"][0]["function"]["name"], "get_weather");
+        assert_eq!(value["messages"][1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(value["messages"][2]["tool_call_id"], "call_1");
+    }
+
+    #[test]
+    fn test_enrich_flattens_tool_messages() {
+        let mut call = create_dummy_node("assistant", "", 100);
+        call.tool_calls = Some(
+            r#"[{"id":"call_1","type":"function","function":{"name":"get_weather","arguments":"{}"}}]"#
+                .to_string(),
+        );
+        let mut result = create_dummy_node("tool", "18C and sunny", 101);
+        result.tool_call_id = Some("call_1".to_string());
+        let chat_request = ChatRequest::new(
+            "test-model".to_string(),
+            vec![create_dummy_message("user", "current user message")],
+        );
+
+        let chat_request = enrich_chat_request(Vec::new(), vec![call, result], &chat_request);
+
+        assert!(chat_request.messages.iter().all(|m| m.role != "tool" && m.tool_calls.is_none()));
+        let contents: Vec<&str> = chat_request.messages.iter().map(|m| m.content.as_str()).collect();
+        assert!(contents.contains(&"Called tool `get_weather` with arguments: {}"));
+        assert!(contents.contains(&"Tool result for call call_1: 18C and sunny"));
+    }
+
+    #[test]
+    fn test_content_parts_round_trip() {
+        let json = r#"{
+            "role": "user",
+            "content": [
+                {"type": "text", "text": "What is in this image?"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png", "detail": "low"}}
+            ]
+        }"#;
+        let message: Message = serde_json::from_str(json).unwrap();
+
+        assert_eq!(message.content, "What is in this image?");
+        assert!(message.has_media());
+        let original: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_value(&message).unwrap(), original);
+    }
+
+    #[test]
+    fn test_push_text_keeps_parts_in_sync() {
+        let mut message: Message = serde_json::from_str(
+            r#"{"role": "system", "content": [{"type": "text", "text": "Be brief."}]}"#,
+        )
+        .unwrap();
+        message.push_text("\nUser: hello");
+
+        assert_eq!(message.content, "Be brief.\nUser: hello");
+        let value = serde_json::to_value(&message).unwrap();
+        assert_eq!(value["content"][1]["text"], "\nUser: hello");
+    }
+
+    #[test]
+    fn test_chain_hash_depends_on_position() {
+        let question = create_dummy_message("user", "What is a graph?");
+        let first = question.chain_hash(None);
+
+        assert_eq!(first, question.chain_hash(None));
+        assert_ne!(first, question.chain_hash(Some(&first)));
+        assert_ne!(first, create_dummy_message("assistant", "What is a graph?").chain_hash(None));
+
+        // Fields a client adds when it sends the history back do not matter
+        let mut resent = question.clone();
+        resent.extra.insert("name".to_string(), "alice".into());
+        assert_eq!(first, resent.chain_hash(None));
+    }
 }
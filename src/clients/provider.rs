@@ -0,0 +1,289 @@
+use std::future::Future;
+
+use anyhow::Error;
+use bytes::Bytes;
+use http_body_util::channel::Sender;
+use serde_json::json;
+use tracing::warn;
+
+use crate::clients::anthropic;
+use crate::clients::openai::chat_completions;
+use crate::clients::openai::model_info::ModelInfo;
+use crate::clients::openai::types::{ChatRequest, ChatResponse, Message};
+use crate::clients::retry::{is_retryable, with_retries};
+use crate::errors::ReservoirError;
+use crate::utils::truncate_messages_if_needed;
+
+/// The API a model is reached through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    /// Any API that speaks OpenAI's `/chat/completions`, which includes
+    /// Ollama, Mistral and Gemini
+    OpenAiCompatible,
+    /// An Azure OpenAI deployment, authenticated with an `api-key` header
+    AzureOpenAi,
+    /// Anthropic's Messages API
+    Anthropic,
+}
+
+/// A completion on its way to a streaming client.
+pub enum CompletionStream {
+    /// The upstream's own event stream, forwarded as it arrives
+    Events(reqwest::Response),
+    /// A provider that is not streamed; its answer is replayed as events
+    Complete(ChatResponse),
+}
+
+async fn complete_with(model_info: &ModelInfo, chat_request: &ChatRequest) -> Result<ChatResponse, Error> {
+    match model_info.provider {
+        Provider::OpenAiCompatible | Provider::AzureOpenAi => {
+            chat_completions::get_completion_message(model_info, chat_request).await
+        }
+        Provider::Anthropic => {
+            anthropic::messages::get_completion_message(model_info, chat_request).await
+        }
+    }
+}
+
+async fn stream_with(model_info: &ModelInfo, chat_request: &ChatRequest) -> Result<CompletionStream, Error> {
+    match model_info.provider {
+        Provider::OpenAiCompatible | Provider::AzureOpenAi => Ok(CompletionStream::Events(
+            chat_completions::get_completion_stream(model_info, chat_request).await?,
+        )),
+        Provider::Anthropic => Ok(CompletionStream::Complete(
+            anthropic::messages::get_completion_message(model_info, chat_request).await?,
+        )),
+    }
+}
+
+/// The first capability the request needs that the model does not declare.
+pub fn missing_capability(chat_request: &ChatRequest, model: &ModelInfo) -> Option<&'static str> {
+    let needed = [
+        ("streaming", chat_request.is_stream()),
+        ("tools", chat_request.extra.contains_key("tools")),
+        ("vision", chat_request.messages.iter().any(Message::has_media)),
+    ];
+    needed
+        .into_iter()
+        .find(|(capability, is_needed)| *is_needed && !model.supports(capability))
+        .map(|(capability, _)| capability)
+}
+
+/// Tries each model of the chain in turn, retrying it as configured for its
+/// provider, and returns the first answer together with the model that gave
+/// it. Fallbacks that cannot serve the request are skipped. Errors that
+/// another model would not fix, such as a bad request, are returned straight
+/// away.
+async fn with_fallbacks<'a, T, F, Fut>(
+    chain: &'a [ModelInfo],
+    chat_request: &ChatRequest,
+    trace_id: &str,
+    send: F,
+) -> Result<(T, &'a ModelInfo), Error>
+where
+    F: Fn(&'a ModelInfo, ChatRequest) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut last_error = None;
+    for (index, model_info) in chain.iter().enumerate() {
+        let mut request = chat_request.clone();
+        // The request was already fitted to the first model, a fallback may
+        // have a smaller context window
+        if index > 0 {
+            if let Some(capability) = missing_capability(&request, model_info) {
+                warn!(
+                    "Trace {}: skipping fallback model {}, it does not support {}",
+                    trace_id, model_info.name, capability
+                );
+                continue;
+            }
+            warn!("Trace {}: falling back to model {}", trace_id, model_info.name);
+            truncate_messages_if_needed(&mut request.messages, model_info.input_tokens, model_info.tokenizer);
+        }
+        let result = with_retries(model_info.max_retries, &model_info.name, trace_id, || {
+            send(model_info, request.clone())
+        })
+        .await;
+        match result {
+            Ok(value) => return Ok((value, model_info)),
+            Err(e) if is_retryable(&e) => last_error = Some(e),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        ReservoirError::Internal("No model to send the request to".to_string()).into()
+    }))
+}
+
+pub async fn get_completion_message<'a>(
+    chain: &'a [ModelInfo],
+    chat_request: &ChatRequest,
+    trace_id: &str,
+) -> Result<(ChatResponse, &'a ModelInfo), Error> {
+    with_fallbacks(chain, chat_request, trace_id, |model_info, request| async move {
+        complete_with(model_info, &request).await
+    })
+    .await
+}
+
+pub async fn get_completion_stream<'a>(
+    chain: &'a [ModelInfo],
+    chat_request: &ChatRequest,
+    trace_id: &str,
+) -> Result<(CompletionStream, &'a ModelInfo), Error> {
+    with_fallbacks(chain, chat_request, trace_id, |model_info, request| async move {
+        stream_with(model_info, &request).await
+    })
+    .await
+}
+
+/// Sends the completion to the client as OpenAI style events and returns
+/// the assistant message to store.
+pub async fn forward_completion_stream(stream: CompletionStream, mut sender: Sender<Bytes>) -> Message {
+    match stream {
+        CompletionStream::Events(response) => {
+            chat_completions::forward_completion_stream(response, sender).await
+        }
+        CompletionStream::Complete(response) => {
+            if sender.send_data(replay_as_events(&response)).await.is_err() {
+                warn!("Client disconnected before the response was sent");
+            }
+            response
+                .choices
+                .into_iter()
+                .next()
+                .map(|c| c.message)
+                .unwrap_or_default()
+        }
+    }
+}
+
+/// Turns a finished completion into the chunks of a streamed one.
+fn replay_as_events(response: &ChatResponse) -> Bytes {
+    let chunk = |delta: serde_json::Value, finish_reason: Option<&str>| {
+        json!({
+            "id": response.id,
+            "object": "chat.completion.chunk",
+            "created": response.created,
+            "model": response.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]
+        })
+    };
+
+    let mut events = Vec::new();
+    if let Some(choice) = response.choices.first() {
+        let mut delta = json!({"role": "assistant", "content": choice.message.content});
+        if let Some(calls) = &choice.message.tool_calls {
+            let calls: Vec<_> = calls
+                .iter()
+                .enumerate()
+                .map(|(index, call)| {
+                    json!({"index": index, "id": call.id, "type": call.kind, "function": call.function})
+                })
+                .collect();
+            delta["tool_calls"] = json!(calls);
+        }
+        events.push(chunk(delta, None));
+        events.push(chunk(json!({}), choice.finish_reason.as_deref()));
+    }
+
+    let mut body: String = events
+        .iter()
+        .map(|event| format!("data: {}\n\n", event))
+        .collect();
+    body.push_str("data: [DONE]\n\n");
+    Bytes::from(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::openai::stream::StreamAccumulator;
+    use crate::utils::Tokenizer;
+    use hyper::StatusCode;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn model(name: &str, capabilities: &[&str]) -> ModelInfo {
+        ModelInfo {
+            input_tokens: 128_000,
+            output_tokens: 4_096,
+            name: name.to_string(),
+            key: "openai-key".to_string(),
+            base_url: "https://api.openai.com/v1/chat/completions".to_string(),
+            provider: Provider::OpenAiCompatible,
+            headers: HashMap::new(),
+            tokenizer: Tokenizer::default(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            timeout: Duration::from_secs(120),
+            client: reqwest::Client::new(),
+            max_retries: 0,
+            fallbacks: Vec::new(),
+            larger_context: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fallbacks_without_capability_are_skipped() {
+        let chain = [
+            model("gpt-4o", &[]),
+            model("gpt-3.5-turbo", &["tools"]),
+            model("claude-sonnet-4-0", &["streaming", "tools"]),
+        ];
+        let request = ChatRequest::from_json(
+            r#"{"model": "gpt-4o", "stream": true, "messages": [{"role": "user", "content": "Hi"}]}"#,
+        )
+        .unwrap();
+        let tried = Mutex::new(Vec::new());
+
+        let (answer, used) = with_fallbacks(&chain, &request, "trace", |model_info, _| {
+            tried.lock().unwrap().push(model_info.name.clone());
+            let failed = tried.lock().unwrap().len() == 1;
+            async move {
+                if failed {
+                    Err(ReservoirError::Upstream {
+                        status: StatusCode::SERVICE_UNAVAILABLE,
+                        body: String::new(),
+                        retry_after: None,
+                    }
+                    .into())
+                } else {
+                    Ok("answer")
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(answer, "answer");
+        assert_eq!(used.name, "claude-sonnet-4-0");
+        assert_eq!(*tried.lock().unwrap(), vec!["gpt-4o", "claude-sonnet-4-0"]);
+    }
+
+    #[test]
+    fn test_replayed_events_accumulate_to_the_same_message() {
+        let response = ChatResponse::from_json(
+            r#"{
+                "id": "msg_1",
+                "model": "claude-sonnet-4-0",
+                "choices": [{
+                    "index": 0,
+                    "finish_reason": "tool_calls",
+                    "message": {"role": "assistant", "content": "Checking",
+                        "tool_calls": [{"id": "toolu_1", "type": "function",
+                            "function": {"name": "get_weather", "arguments": "{}"}}]}
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let mut accumulator = StreamAccumulator::new();
+        accumulator.push(&replay_as_events(&response));
+
+        assert!(accumulator.is_done());
+        let message = accumulator.into_message();
+        assert_eq!(message.content, "Checking");
+        assert_eq!(message.tool_calls.unwrap()[0].function.name, "get_weather");
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::env;
+
+use once_cell::sync::Lazy;
+use tracing::warn;
+
+use crate::clients::openai::model_info::{models_url, ModelInfo, ModelSource};
+use crate::clients::provider::Provider;
+use crate::errors::ReservoirError;
+use crate::repos::config::{
+    get_azure_config, get_model_configs, get_provider_configs, AzureConfig, ModelConfig,
+    ProviderConfig, ProviderKind,
+};
+use crate::utils::Tokenizer;
+
+/// Name of the provider the `[azure]` section becomes
+pub const AZURE_PROVIDER: &str = "azure";
+/// Name of the built in OpenAI provider
+pub const OPENAI_PROVIDER: &str = "openai";
+const RSV_OPENAI_BASE_URL: &str = "RSV_OPENAI_BASE_URL";
+const RSV_OLLAMA_BASE_URL: &str = "RSV_OLLAMA_BASE_URL";
+const RSV_MISTRAL_BASE_URL: &str = "RSV_MISTRAL_BASE_URL";
+const RSV_ANTHROPIC_BASE_URL: &str = "RSV_ANTHROPIC_BASE_URL";
+
+fn openai_base_url() -> String {
+    env::var(RSV_OPENAI_BASE_URL)
+        .unwrap_or_else(|_| "https://api.openai.com/v1/chat/completions".to_string())
+}
+
+fn ollama_base_url() -> String {
+    env::var(RSV_OLLAMA_BASE_URL).unwrap_or_else(|_| {
+        let root = env::var("OLLAMA_BASE_URL").unwrap_or("http://localhost:11434".to_string());
+        format!("{}/v1/chat/completions", root.trim_end_matches('/'))
+    })
+}
+
+fn mistral_base_url() -> String {
+    env::var(RSV_MISTRAL_BASE_URL)
+        .unwrap_or_else(|_| "https://api.mistral.ai/v1/chat/completions".to_string())
+}
+
+fn anthropic_base_url() -> String {
+    env::var(RSV_ANTHROPIC_BASE_URL)
+        .unwrap_or_else(|_| "https://api.anthropic.com/v1/messages".to_string())
+}
+
+fn gemini_base_url() -> String {
+    "https://generativelanguage.googleapis.com/v1beta/openai/chat/completions".to_string()
+}
+
+fn builtin_provider(name: &str, kind: ProviderKind, base_url: String, key_env: &str) -> ProviderConfig {
+    ProviderConfig {
+        name: name.to_string(),
+        kind,
+        base_url,
+        api_key: None,
+        api_key_env: Some(key_env.to_string()),
+        headers: Default::default(),
+        api_version: None,
+        timeout_secs: None,
+        connect_timeout_secs: None,
+        max_retries: None,
+    }
+}
+
+fn builtin_providers() -> Vec<ProviderConfig> {
+    vec![
+        builtin_provider(OPENAI_PROVIDER, ProviderKind::Openai, openai_base_url(), "OPENAI_API_KEY"),
+        builtin_provider("ollama", ProviderKind::Ollama, ollama_base_url(), "OLLAMA_API_KEY"),
+        builtin_provider("mistral", ProviderKind::Openai, mistral_base_url(), "MISTRAL_API_KEY"),
+        builtin_provider("gemini", ProviderKind::Openai, gemini_base_url(), "GEMINI_API_KEY"),
+        builtin_provider(
+            "anthropic",
+            ProviderKind::Anthropic,
+            anthropic_base_url(),
+            "ANTHROPIC_API_KEY",
+        ),
+    ]
+}
+
+fn builtin_model(name: &str, provider: &str, context_window: usize, output_tokens: usize) -> ModelConfig {
+    ModelConfig {
+        name: name.to_string(),
+        provider: provider.to_string(),
+        upstream: None,
+        context_window,
+        output_tokens,
+        tokenizer: Tokenizer::default(),
+        capabilities: Vec::new(),
+        aliases: Vec::new(),
+        fallbacks: Vec::new(),
+        larger_context: None,
+    }
+}
+
+fn builtin_models() -> Vec<ModelConfig> {
+    vec![
+        builtin_model("gpt-4.1", "openai", 128_000, 4_096),
+        builtin_model("gpt-4o", "openai", 128_000, 4_096),
+        builtin_model("gpt-4o-mini", "openai", 48_000, 4_096),
+        builtin_model("llama3.2", "ollama", 128_000, 2_048),
+        builtin_model("mistral-large-2402", "mistral", 128_000, 2_048),
+        builtin_model("gemini-2.0-flash", "gemini", 128_000, 2_048),
+        // Claude models all share the same context window
+        builtin_model("claude-*", "anthropic", 200_000, 8_192),
+    ]
+}
+
+/// A client that gives up on a provider that does not connect, or stops
+/// sending its answer, instead of waiting forever. Streams are not cut off
+/// as long as chunks keep arriving.
+fn build_client(provider: &ProviderConfig) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(provider.connect_timeout())
+        .read_timeout(provider.timeout())
+        .build()
+        .unwrap_or_else(|e| {
+            warn!("Could not build the HTTP client for {}: {}", provider.name, e);
+            reqwest::Client::new()
+        })
+}
+
+/// The providers and models Reservoir can send requests to: the built in
+/// ones, overridden and extended by `[[providers]]` and `[[models]]` in
+/// `reservoir.toml`.
+pub struct ModelRegistry {
+    providers: Vec<ProviderConfig>,
+    models: Vec<ModelConfig>,
+    /// One HTTP client per provider, so connections are pooled across
+    /// requests
+    clients: HashMap<String, reqwest::Client>,
+}
+
+static MODEL_REGISTRY: Lazy<ModelRegistry> = Lazy::new(|| {
+    ModelRegistry::new(
+        get_provider_configs().to_vec(),
+        get_model_configs().to_vec(),
+    )
+    .with_azure(get_azure_config())
+});
+
+pub fn get_model_registry() -> &'static ModelRegistry {
+    &MODEL_REGISTRY
+}
+
+impl ModelRegistry {
+    /// Builds the registry from configured entries. Configured providers and
+    /// models replace built in ones of the same name, and configured models
+    /// are matched first.
+    pub fn new(providers: Vec<ProviderConfig>, models: Vec<ModelConfig>) -> Self {
+        let mut all_providers = providers.clone();
+        all_providers.extend(
+            builtin_providers()
+                .into_iter()
+                .filter(|p| !providers.iter().any(|c| c.name == p.name)),
+        );
+        let mut all_models = models.clone();
+        all_models.extend(
+            builtin_models()
+                .into_iter()
+                .filter(|m| !models.iter().any(|c| c.name == m.name)),
+        );
+        let clients = all_providers
+            .iter()
+            .map(|provider| (provider.name.clone(), build_client(provider)))
+            .collect();
+        ModelRegistry {
+            providers: all_providers,
+            models: all_models,
+            clients,
+        }
+    }
+
+    /// Adds the `[azure]` section as a provider named `azure`, replacing a
+    /// configured one of that name, and sends every model listed under its
+    /// `deployments` there. The models keep their token limits and other
+    /// settings, only the provider and the name sent upstream change.
+    pub fn with_azure(mut self, azure: Option<&AzureConfig>) -> Self {
+        let Some(azure) = azure else {
+            return self;
+        };
+        let provider = ProviderConfig {
+            name: AZURE_PROVIDER.to_string(),
+            kind: ProviderKind::Azure,
+            base_url: azure.endpoint.clone(),
+            api_key: azure.api_key.clone(),
+            api_key_env: Some("AZURE_OPENAI_API_KEY".to_string()),
+            headers: Default::default(),
+            api_version: Some(azure.api_version.clone()),
+            timeout_secs: None,
+            connect_timeout_secs: None,
+            max_retries: None,
+        };
+        self.clients
+            .insert(provider.name.clone(), build_client(&provider));
+        self.providers.retain(|p| p.name != provider.name);
+        self.providers.push(provider);
+
+        let mut models: Vec<ModelConfig> = azure
+            .deployments
+            .iter()
+            .map(|(name, deployment)| {
+                let mut model = self
+                    .find_model(name)
+                    .cloned()
+                    .unwrap_or_else(|| builtin_model(name, AZURE_PROVIDER, 128_000, 4_096));
+                model.name = name.clone();
+                model.provider = AZURE_PROVIDER.to_string();
+                model.upstream = Some(deployment.clone());
+                model
+            })
+            .collect();
+        models.append(&mut self.models);
+        self.models = models;
+        self
+    }
+
+    fn provider(&self, name: &str) -> Option<&ProviderConfig> {
+        self.providers.iter().find(|p| p.name == name)
+    }
+
+    /// The HTTP client and retry count of a provider, for requests that are
+    /// not sent to a model, such as embeddings.
+    pub fn provider_client(&self, name: &str) -> Option<(reqwest::Client, u32)> {
+        let provider = self.provider(name)?;
+        let client = self.clients.get(name).cloned().unwrap_or_default();
+        Some((client, provider.max_retries()))
+    }
+
+    /// Finds the entry for a name, exact names and aliases before patterns.
+    fn find_model(&self, name: &str) -> Option<&ModelConfig> {
+        self.models
+            .iter()
+            .find(|m| m.name == name || m.aliases.iter().any(|a| a == name))
+            .or_else(|| {
+                self.models.iter().find(|m| {
+                    m.name
+                        .strip_suffix('*')
+                        .is_some_and(|prefix| name.starts_with(prefix))
+                })
+            })
+    }
+
+    fn resolve(&self, name: &str) -> Result<ModelInfo, ReservoirError> {
+        let model = self.find_model(name).ok_or_else(|| {
+            ReservoirError::ModelNotFound(format!(
+                "The model '{}' is not configured. Add it to the [[models]] section of reservoir.toml.",
+                name
+            ))
+        })?;
+        let provider = self.provider(&model.provider).ok_or_else(|| {
+            ReservoirError::Internal(format!(
+                "The model '{}' uses the unknown provider '{}'",
+                model.name, model.provider
+            ))
+        })?;
+
+        // Aliases and patterns are sent upstream by their real name
+        let upstream = match &model.upstream {
+            Some(upstream) => upstream.clone(),
+            None if model.name.ends_with('*') => name.to_string(),
+            None => model.name.clone(),
+        };
+        let (base_url, kind) = match provider.kind {
+            ProviderKind::Openai | ProviderKind::Ollama => {
+                (provider.base_url.clone(), Provider::OpenAiCompatible)
+            }
+            ProviderKind::Anthropic => (provider.base_url.clone(), Provider::Anthropic),
+            ProviderKind::Azure => (
+                format!(
+                    "{}/openai/deployments/{}/chat/completions?api-version={}",
+                    provider.base_url.trim_end_matches('/'),
+                    upstream,
+                    provider.api_version.as_deref().unwrap_or("2024-10-21")
+                ),
+                Provider::AzureOpenAi,
+            ),
+        };
+
+        Ok(ModelInfo {
+            input_tokens: model.context_window,
+            output_tokens: model.output_tokens,
+            name: upstream,
+            key: provider.api_key(),
+            base_url,
+            provider: kind,
+            headers: provider.headers.clone(),
+            tokenizer: model.tokenizer,
+            capabilities: model.capabilities.clone(),
+            timeout: provider.timeout(),
+            client: self
+                .clients
+                .get(&provider.name)
+                .cloned()
+                .unwrap_or_default(),
+            max_retries: provider.max_retries(),
+            fallbacks: model.fallbacks.clone(),
+            larger_context: model.larger_context.clone(),
+        })
+    }
+
+    /// The model followed by its fallbacks, in the order they are tried.
+    /// Fallbacks that are not configured are skipped.
+    pub fn resolve_chain(&self, name: &str) -> Result<Vec<ModelInfo>, ReservoirError> {
+        let primary = self.resolve(name)?;
+        let mut chain = vec![primary.clone()];
+        for fallback in &primary.fallbacks {
+            match self.resolve(fallback) {
+                Ok(model) => chain.push(model),
+                Err(e) => warn!("Skipping fallback {} of {}: {}", fallback, name, e),
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Names of the models that can be requested, with their provider.
+    /// Patterns are left out.
+    pub fn model_names(&self) -> Vec<(String, String)> {
+        self.models
+            .iter()
+            .flat_map(|m| {
+                std::iter::once(&m.name)
+                    .chain(m.aliases.iter())
+                    .filter(|name| !name.ends_with('*'))
+                    .map(|name| (name.clone(), m.provider.clone()))
+            })
+            .collect()
+    }
+
+    /// The upstreams to discover models from. Providers without an API key
+    /// are skipped, Ollama is always asked since it runs locally.
+    pub fn model_sources(&self) -> Vec<ModelSource> {
+        self.providers
+            .iter()
+            .filter_map(|provider| match provider.kind {
+                ProviderKind::Openai => {
+                    let key = provider.api_key();
+                    if key.is_empty() {
+                        return None;
+                    }
+                    Some(ModelSource::OpenAiCompatible {
+                        owned_by: provider.name.clone(),
+                        url: models_url(&provider.base_url),
+                        key,
+                    })
+                }
+                ProviderKind::Ollama => {
+                    let root = provider
+                        .base_url
+                        .strip_suffix("/v1/chat/completions")
+                        .unwrap_or(&provider.base_url);
+                    Some(ModelSource::Ollama {
+                        url: format!("{}/api/tags", root.trim_end_matches('/')),
+                    })
+                }
+                ProviderKind::Azure | ProviderKind::Anthropic => None,
+            })
+            .collect()
+    }
+
+    pub fn is_known(&self, name: &str) -> bool {
+        self.find_model(name).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configured() -> ModelRegistry {
+        let providers = vec![ProviderConfig {
+            name: "groq".to_string(),
+            kind: ProviderKind::Openai,
+            base_url: "https://api.groq.com/openai/v1/chat/completions".to_string(),
+            api_key: Some("groq-key".to_string()),
+            api_key_env: None,
+            headers: [("x-team".to_string(), "research".to_string())].into(),
+            api_version: None,
+            timeout_secs: Some(30),
+            connect_timeout_secs: None,
+            max_retries: None,
+        }];
+        let mut llama = builtin_model("llama-3.3-70b-versatile", "groq", 131_072, 8_192);
+        llama.aliases = vec!["groq-llama".to_string()];
+        llama.tokenizer = Tokenizer::Cl100kBase;
+        let mut gpt = builtin_model("gpt-4o", "openai", 64_000, 4_096);
+        gpt.fallbacks = vec!["groq-llama".to_string(), "qwen2.5".to_string(), "llama3.2".to_string()];
+        ModelRegistry::new(providers, vec![llama, gpt])
+    }
+
+    #[test]
+    fn test_configured_model_and_alias() {
+        let registry = configured();
+        let model = registry.resolve("groq-llama").unwrap();
+
+        assert_eq!(model.name, "llama-3.3-70b-versatile");
+        assert_eq!(model.input_tokens, 131_072);
+        assert_eq!(model.key, "groq-key");
+        assert_eq!(model.tokenizer, Tokenizer::Cl100kBase);
+        assert_eq!(model.headers["x-team"], "research");
+        assert_eq!(model.timeout.as_secs(), 30);
+    }
+
+    #[test]
+    fn test_configured_model_overrides_builtin() {
+        let registry = configured();
+        assert_eq!(registry.resolve("gpt-4o").unwrap().input_tokens, 64_000);
+        assert_eq!(registry.resolve("gpt-4o-mini").unwrap().input_tokens, 48_000);
+    }
+
+    #[test]
+    fn test_pattern_keeps_the_requested_name() {
+        let registry = configured();
+        let model = registry.resolve("claude-3-7-sonnet-latest").unwrap();
+
+        assert_eq!(model.provider, Provider::Anthropic);
+        assert_eq!(model.name, "claude-3-7-sonnet-latest");
+        assert!(!registry
+            .model_names()
+            .iter()
+            .any(|(name, _)| name.ends_with('*')));
+    }
+
+    #[test]
+    fn test_unknown_model_is_an_error() {
+        let error = configured().resolve("qwen2.5").unwrap_err();
+        assert_eq!(error.status().as_u16(), 404);
+        assert!(error.to_string().contains("qwen2.5"));
+    }
+
+    #[test]
+    fn test_chain_skips_unknown_fallbacks() {
+        let registry = configured();
+        let chain: Vec<String> = registry
+            .resolve_chain("gpt-4o")
+            .unwrap()
+            .into_iter()
+            .map(|model| model.name)
+            .collect();
+        assert_eq!(chain, ["gpt-4o", "llama-3.3-70b-versatile", "llama3.2"]);
+    }
+
+    #[test]
+    fn test_provider_client() {
+        let registry = configured();
+        let (_, max_retries) = registry.provider_client(OPENAI_PROVIDER).unwrap();
+        assert_eq!(max_retries, 2);
+        assert!(registry.provider_client(AZURE_PROVIDER).is_none());
+    }
+
+    #[test]
+    fn test_azure_deployments_become_a_provider() {
+        let azure = AzureConfig {
+            endpoint: "https://my-resource.openai.azure.com".to_string(),
+            api_key: Some("azure-key".to_string()),
+            api_version: "2024-10-21".to_string(),
+            deployments: [
+                ("gpt-4o".to_string(), "gpt4o-prod".to_string()),
+                ("o3".to_string(), "o3-prod".to_string()),
+            ]
+            .into(),
+            embedding_deployment: None,
+        };
+        let registry = configured().with_azure(Some(&azure));
+
+        let model = registry.resolve("gpt-4o").unwrap();
+        assert_eq!(model.provider, Provider::AzureOpenAi);
+        assert_eq!(model.key, "azure-key");
+        assert_eq!(model.input_tokens, 64_000);
+        assert_eq!(model.fallbacks.len(), 3);
+        assert_eq!(
+            model.base_url,
+            "https://my-resource.openai.azure.com/openai/deployments/gpt4o-prod/chat/completions?api-version=2024-10-21"
+        );
+        assert_eq!(registry.resolve("o3").unwrap().provider, Provider::AzureOpenAi);
+        assert_eq!(registry.resolve("llama3.2").unwrap().provider, Provider::OpenAiCompatible);
+    }
+}
@@ -0,0 +1,161 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Error;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::errors::ReservoirError;
+
+const BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between attempts. A provider asking to wait longer than
+/// this is given up on, so a fallback model can answer instead.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// True for errors that may go away when the request is sent again: rate
+/// limits, server errors and providers that could not be reached.
+pub fn is_retryable(error: &Error) -> bool {
+    match error.downcast_ref::<ReservoirError>() {
+        Some(ReservoirError::Upstream { status, .. }) => {
+            status.as_u16() == 429 || status.is_server_error()
+        }
+        Some(ReservoirError::UpstreamUnavailable(_)) => true,
+        _ => false,
+    }
+}
+
+fn retry_after(error: &Error) -> Option<Duration> {
+    match error.downcast_ref::<ReservoirError>() {
+        Some(ReservoirError::Upstream { retry_after, .. }) => *retry_after,
+        _ => None,
+    }
+}
+
+/// Reads a `Retry-After` header given in seconds. The HTTP date form is
+/// rarely used by providers and is ignored.
+pub fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// A random duration between zero and `max`.
+fn jitter(max: Duration) -> Duration {
+    let fraction = (Uuid::new_v4().as_u128() % 1_000) as f64 / 1_000.0;
+    max.mul_f64(fraction)
+}
+
+/// How long to wait before the given retry, counting from zero. A
+/// `Retry-After` from the provider is respected, otherwise the delay
+/// doubles every attempt with up to half of it added as jitter. `None`
+/// means the wait would be too long to be worth it.
+fn delay_for(retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+    if let Some(retry_after) = retry_after {
+        return (retry_after <= MAX_DELAY).then_some(retry_after);
+    }
+    let delay = BASE_DELAY.saturating_mul(2u32.saturating_pow(retry)).min(MAX_DELAY);
+    Some(delay + jitter(delay / 2))
+}
+
+/// Runs `attempt` until it succeeds, fails with an error that is not worth
+/// retrying, or `max_retries` retries have been made.
+pub async fn with_retries<T, F, Fut>(
+    max_retries: u32,
+    model: &str,
+    trace_id: &str,
+    mut attempt: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut retry = 0;
+    loop {
+        let error = match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        warn!(
+            "Trace {}: attempt {} with model {} failed: {}",
+            trace_id,
+            retry + 1,
+            model,
+            error
+        );
+        if retry >= max_retries || !is_retryable(&error) {
+            return Err(error);
+        }
+        let Some(delay) = delay_for(retry, retry_after(&error)) else {
+            return Err(error);
+        };
+        tokio::time::sleep(delay).await;
+        retry += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn upstream(status: StatusCode) -> Error {
+        ReservoirError::Upstream {
+            status,
+            body: String::new(),
+            retry_after: Some(Duration::ZERO),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        assert!(is_retryable(&upstream(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(is_retryable(&upstream(StatusCode::BAD_GATEWAY)));
+        assert!(!is_retryable(&upstream(StatusCode::BAD_REQUEST)));
+        assert!(!is_retryable(&ReservoirError::BadRequest("bad".to_string()).into()));
+    }
+
+    #[test]
+    fn test_delay_grows_and_respects_retry_after() {
+        let first = delay_for(0, None).unwrap();
+        let third = delay_for(2, None).unwrap();
+        assert!(first >= BASE_DELAY && first <= BASE_DELAY * 3 / 2);
+        assert!(third >= BASE_DELAY * 4);
+        assert!(delay_for(20, None).unwrap() <= MAX_DELAY * 3 / 2);
+
+        assert_eq!(
+            delay_for(0, Some(Duration::from_secs(2))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(delay_for(0, Some(Duration::from_secs(600))), None);
+    }
+
+    #[tokio::test]
+    async fn test_with_retries_stops_on_success_and_on_client_errors() {
+        let calls = AtomicU32::new(0);
+        let result = with_retries(3, "gpt-4o", "trace", || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(upstream(StatusCode::SERVICE_UNAVAILABLE)),
+                _ => Ok("answer"),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "answer");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let calls = AtomicU32::new(0);
+        let result: Result<(), Error> = with_retries(3, "gpt-4o", "trace", || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(upstream(StatusCode::BAD_REQUEST))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,137 @@
+use tracing::{info, warn};
+
+use crate::repos::config::{RouteConfig, RouteRule};
+
+/// What is known about a request when its route is chosen.
+#[derive(Debug, Clone)]
+pub struct RouteContext<'a> {
+    pub partition: &'a str,
+    pub instance: &'a str,
+    /// Tokens in the prompt as sent by the client, before enrichment
+    pub prompt_tokens: usize,
+    pub has_images: bool,
+    /// Local hour of the day, 0 to 23
+    pub hour: u32,
+}
+
+/// Parses `start-end` and checks if the hour falls in it. The end hour is
+/// not included, and a range may wrap around midnight.
+fn in_hours(hours: &str, hour: u32) -> Option<bool> {
+    let (start, end) = hours.split_once('-')?;
+    let start: u32 = start.trim().parse().ok()?;
+    let end: u32 = end.trim().parse().ok()?;
+    Some(if start <= end {
+        hour >= start && hour < end
+    } else {
+        hour >= start || hour < end
+    })
+}
+
+impl RouteRule {
+    fn matches(&self, context: &RouteContext) -> bool {
+        let hours_match = match &self.hours {
+            Some(hours) => in_hours(hours, context.hour).unwrap_or_else(|| {
+                warn!("Ignoring route rule with invalid hours '{}'", hours);
+                false
+            }),
+            None => true,
+        };
+        hours_match
+            && self.partition.as_deref().is_none_or(|p| p == context.partition)
+            && self.instance.as_deref().is_none_or(|i| i == context.instance)
+            && self.min_tokens.is_none_or(|min| context.prompt_tokens >= min)
+            && self.max_tokens.is_none_or(|max| context.prompt_tokens <= max)
+            && self.has_images.is_none_or(|images| images == context.has_images)
+    }
+}
+
+/// Returns the model a logical model name routes to, or `None` when the
+/// name is not a route.
+pub fn route_model(routes: &[RouteConfig], requested: &str, context: &RouteContext) -> Option<String> {
+    let route = routes.iter().find(|r| r.name == requested)?;
+    let model = route
+        .rules
+        .iter()
+        .find(|rule| rule.matches(context))
+        .map(|rule| rule.model.clone())
+        .unwrap_or_else(|| route.default.clone());
+    info!("Routed '{}' to '{}'", requested, model);
+    Some(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes() -> Vec<RouteConfig> {
+        vec![RouteConfig {
+            name: "smart".to_string(),
+            default: "gpt-4.1".to_string(),
+            rules: vec![
+                RouteRule {
+                    model: "llama3.2".to_string(),
+                    partition: Some("private".to_string()),
+                    ..Default::default()
+                },
+                RouteRule {
+                    model: "gpt-4o".to_string(),
+                    has_images: Some(true),
+                    ..Default::default()
+                },
+                RouteRule {
+                    model: "gemini-2.0-flash".to_string(),
+                    min_tokens: Some(50_000),
+                    ..Default::default()
+                },
+                RouteRule {
+                    model: "gpt-4o-mini".to_string(),
+                    hours: Some("22-6".to_string()),
+                    ..Default::default()
+                },
+            ],
+        }]
+    }
+
+    fn context() -> RouteContext<'static> {
+        RouteContext {
+            partition: "work",
+            instance: "chat",
+            prompt_tokens: 100,
+            has_images: false,
+            hour: 12,
+        }
+    }
+
+    #[test]
+    fn test_rules_in_order() {
+        let routes = routes();
+        let route = |context: RouteContext| route_model(&routes, "smart", &context);
+
+        assert_eq!(route(context()).as_deref(), Some("gpt-4.1"));
+        assert_eq!(
+            route(RouteContext { partition: "private", has_images: true, ..context() }).as_deref(),
+            Some("llama3.2")
+        );
+        assert_eq!(
+            route(RouteContext { has_images: true, ..context() }).as_deref(),
+            Some("gpt-4o")
+        );
+        assert_eq!(
+            route(RouteContext { prompt_tokens: 60_000, ..context() }).as_deref(),
+            Some("gemini-2.0-flash")
+        );
+        assert_eq!(
+            route(RouteContext { hour: 23, ..context() }).as_deref(),
+            Some("gpt-4o-mini")
+        );
+        assert_eq!(
+            route(RouteContext { hour: 6, ..context() }).as_deref(),
+            Some("gpt-4.1")
+        );
+    }
+
+    #[test]
+    fn test_not_a_route() {
+        assert_eq!(route_model(&routes(), "gpt-4o", &context()), None);
+    }
+}
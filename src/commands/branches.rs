@@ -0,0 +1,190 @@
+use crate::clients::openai::types::Message;
+use crate::errors::ReservoirError;
+use crate::models::message_node::{load_media, MessageNode};
+use crate::repos::message::{AnyMessageRepository, MessageRepository};
+use anyhow::Error;
+use clap::Parser;
+use serde::Serialize;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "List the branches of a conversation, or walk one", long_about = None)]
+pub struct BranchesSubCommand {
+    /// Id of any message in the conversation
+    pub message_id: String,
+    /// Print the conversation that ends at the message instead of listing
+    /// the branches
+    #[arg(short, long)]
+    pub walk: bool,
+    /// Partition of the conversation (defaults to "default")
+    #[arg(short, long)]
+    pub partition: Option<String>,
+    /// Instance of the conversation (defaults to partition)
+    #[arg(short, long)]
+    pub instance: Option<String>,
+}
+
+/// One alternative version of a conversation, from its first message to
+/// the last message of the branch.
+#[derive(Debug, Serialize)]
+pub struct Branch {
+    /// Id of the last message, walk the branch with it
+    pub id: String,
+    /// Number of messages in the branch
+    pub length: usize,
+    /// Id of the last message the branch shares with an earlier branch,
+    /// the point where an edit or regeneration forked it off
+    pub forked_from: Option<String>,
+    pub role: String,
+    pub content: String,
+}
+
+/// A message of a walked conversation, with the id to list its branches by.
+#[derive(Debug, Serialize)]
+pub struct ConversationMessage {
+    pub id: String,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+/// Messages from other partitions or instances are reported as not found,
+/// so a key scoped to one partition cannot read another one's messages.
+fn check_scope(nodes: &[MessageNode], partition: &str, instance: &str, message_id: &str) -> Result<(), Error> {
+    if nodes
+        .iter()
+        .any(|node| node.partition != partition || node.instance != instance)
+    {
+        return Err(ReservoirError::NotFound(format!("Message {} not found", message_id)).into());
+    }
+    Ok(())
+}
+
+/// For every branch after the first, the id of the last message it shares
+/// with one of the branches before it.
+fn fork_points(paths: &[Vec<String>]) -> Vec<Option<String>> {
+    paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| {
+            paths[..index]
+                .iter()
+                .map(|earlier| {
+                    path.iter()
+                        .zip(earlier)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                })
+                .max()
+                .filter(|shared| *shared > 0)
+                .map(|shared| path[shared - 1].clone())
+        })
+        .collect()
+}
+
+pub async fn execute_branches(
+    repo: &AnyMessageRepository,
+    partition: String,
+    instance: String,
+    message_id: String,
+) -> Result<Vec<Branch>, Error> {
+    let leaves = repo.get_branch_leaves(&message_id).await?;
+    check_scope(&leaves, &partition, &instance, &message_id)?;
+
+    let mut paths = Vec::with_capacity(leaves.len());
+    for leaf in &leaves {
+        let path = repo.get_conversation_path(&leaf.id).await?;
+        paths.push(path.into_iter().map(|node| node.id).collect::<Vec<_>>());
+    }
+    let forks = fork_points(&paths);
+
+    Ok(leaves
+        .into_iter()
+        .zip(paths.iter().zip(forks))
+        .map(|(leaf, (path, forked_from))| Branch {
+            id: leaf.id.clone(),
+            length: path.len(),
+            forked_from,
+            role: leaf.role.clone(),
+            content: leaf.content.clone().unwrap_or_default(),
+        })
+        .collect())
+}
+
+pub async fn execute_walk(
+    repo: &AnyMessageRepository,
+    partition: String,
+    instance: String,
+    message_id: String,
+) -> Result<Vec<ConversationMessage>, Error> {
+    let mut path = repo.get_conversation_path(&message_id).await?;
+    check_scope(&path, &partition, &instance, &message_id)?;
+    load_media(&mut path).await;
+    Ok(path
+        .iter()
+        .map(|node| ConversationMessage {
+            id: node.id.clone(),
+            message: node.to_message(),
+        })
+        .collect())
+}
+
+pub async fn run(repo: &AnyMessageRepository, cmd: &BranchesSubCommand) -> Result<(), Error> {
+    let partition = cmd
+        .partition
+        .clone()
+        .unwrap_or_else(|| "default".to_string());
+    let instance = cmd.instance.clone().unwrap_or_else(|| partition.clone());
+
+    if cmd.walk {
+        for entry in execute_walk(repo, partition, instance, cmd.message_id.clone()).await? {
+            println!("[{}] {}: {}", entry.id, entry.message.role, entry.message.content);
+        }
+        return Ok(());
+    }
+
+    for (i, branch) in execute_branches(repo, partition, instance, cmd.message_id.clone())
+        .await?
+        .iter()
+        .enumerate()
+    {
+        let forked = match &branch.forked_from {
+            Some(id) => format!(", forked after {}", id),
+            None => String::new(),
+        };
+        println!(
+            "{}. [{}] {} messages{}\n   {}: {}",
+            i + 1,
+            branch.id,
+            branch.length,
+            forked,
+            branch.role,
+            branch.content
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_fork_points() {
+        let paths = vec![
+            path(&["q1", "a1", "q2", "a2"]),
+            // The second answer was regenerated
+            path(&["q1", "a1", "q2", "a2b"]),
+            // The second question was edited
+            path(&["q1", "a1", "q2c", "a2c"]),
+            path(&["other"]),
+        ];
+
+        assert_eq!(
+            fork_points(&paths),
+            vec![None, Some("q2".to_string()), Some("a1".to_string()), None]
+        );
+    }
+}
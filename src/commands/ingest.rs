@@ -1,6 +1,6 @@
 use crate::repos::message::{AnyMessageRepository, MessageRepository};
 use crate::models::message_node::MessageNode;
-use crate::clients::openai::embeddings::get_embeddings_for_text;
+use crate::clients::openai::embeddings::get_embedding_for_text;
 use crate::clients::openai::types::Message;
 use anyhow::Error;
 use uuid::Uuid;
@@ -28,10 +28,12 @@ pub async fn run(repo: &AnyMessageRepository, cmd: &IngestSubCommand) -> Result<
     let message = Message {
         role,
         content: content.clone(),
+        ..Default::default()
     };
-    let embedding = get_embeddings_for_text(&content).await?.first().unwrap().embedding.clone();
+    let embedding = get_embedding_for_text(&content).await?;
     let node = MessageNode::from_message(&message, &trace_id, &partition, &instance, embedding);
     repo.save_message_node(&node).await?;
+    repo.connect_synapses(&trace_id, &partition, &instance).await?;
     println!("Saved message with trace_id: {}", trace_id);
     Ok(())
 } 
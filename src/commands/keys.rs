@@ -0,0 +1,73 @@
+use crate::args::{CreateKeySubCommand, KeysAction, KeysSubCommand};
+use crate::models::api_key::{ApiKey, Permission};
+use crate::repos::api_key::{ApiKeyRepository, Neo4jApiKeyRepository};
+use anyhow::Error;
+
+fn or_all(values: &[String]) -> Vec<String> {
+    if values.is_empty() {
+        vec!["*".to_string()]
+    } else {
+        values.to_vec()
+    }
+}
+
+async fn create(repo: &Neo4jApiKeyRepository, cmd: &CreateKeySubCommand) -> Result<(), Error> {
+    let permissions = if cmd.permissions.is_empty() {
+        vec![Permission::Read, Permission::Write, Permission::Search]
+    } else {
+        cmd.permissions
+            .iter()
+            .map(|p| {
+                Permission::parse(p).ok_or_else(|| {
+                    Error::msg(format!(
+                        "Unknown permission '{}', expected read, write or search",
+                        p
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    let (key, secret) = ApiKey::generate(
+        cmd.name.clone(),
+        or_all(&cmd.partition),
+        or_all(&cmd.instance),
+        permissions,
+    );
+    repo.save_api_key(&key).await?;
+    println!("Created key '{}' with id {}", key.name, key.id);
+    println!("{}", secret);
+    println!("Store it somewhere safe, it cannot be shown again.");
+    Ok(())
+}
+
+pub async fn run(repo: &Neo4jApiKeyRepository, cmd: &KeysSubCommand) -> Result<(), Error> {
+    match &cmd.action {
+        KeysAction::Create(create_cmd) => create(repo, create_cmd).await,
+        KeysAction::List => {
+            let keys = repo.get_api_keys().await?;
+            if keys.is_empty() {
+                println!("No keys, Reservoir is open to anyone who can reach it");
+            }
+            for key in keys {
+                println!(
+                    "{} {} partitions=[{}] instances=[{}] permissions=[{}]",
+                    key.id,
+                    key.name,
+                    key.partitions.join(","),
+                    key.instances.join(","),
+                    key.permissions.join(",")
+                );
+            }
+            Ok(())
+        }
+        KeysAction::Revoke(revoke_cmd) => {
+            if repo.delete_api_key(&revoke_cmd.id).await? {
+                println!("Revoked key {}", revoke_cmd.id);
+            } else {
+                println!("No key with id {}", revoke_cmd.id);
+            }
+            Ok(())
+        }
+    }
+}
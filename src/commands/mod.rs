@@ -4,4 +4,7 @@ pub mod export;
 pub mod import;
 pub mod view;
 pub mod search;
+pub mod branches;
 pub mod ingest;
+pub mod keys;
+pub mod synapses;
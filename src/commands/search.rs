@@ -1,6 +1,10 @@
-use crate::clients::openai::embeddings::get_embeddings_for_text;
+use crate::clients::openai::embeddings::get_embedding_for_text;
 use crate::clients::openai::types::Message;
+use crate::models::message_node::{load_media, MessageNode};
+use crate::repos::config::get_enrichment_settings;
 use crate::repos::message::{AnyMessageRepository, MessageRepository};
+use crate::models::strategy::Strategy;
+use crate::services::context::{ContextQuery, ContextStrategy};
 use crate::utils::deduplicate_message_nodes;
 use anyhow::Error;
 use clap::Parser;
@@ -20,10 +24,16 @@ pub struct SearchSubCommand {
     /// Instance to search (defaults to partition)
     #[arg(short, long)]
     pub instance: Option<String>,
-    /// Use the same search strategy as RAG does when injecting
-    /// into the model
+    /// Expand the semantic results with the messages linked to them by
+    /// synapses, like the graph_walk strategy. Implies --semantic and
+    /// --deduplicate
     #[arg(short, long)]
     pub link: bool,
+    /// Find the messages the way this enrichment strategy does for a chat
+    /// request. Overrides --link, and --semantic and --deduplicate are
+    /// ignored
+    #[arg(long, value_enum)]
+    pub strategy: Option<Strategy>,
     /// Deuplicate first similarity results
     #[arg(short, long)]
     pub deduplicate: bool,
@@ -36,6 +46,7 @@ pub async fn run(repo: &AnyMessageRepository, cmd: &SearchSubCommand) -> Result<
         .unwrap_or_else(|| "default".to_string());
     let instance = cmd.instance.clone().unwrap_or_else(|| partition.clone());
     let count = 10; // Default count for CLI search
+    let strategy = cmd.strategy.or(cmd.link.then_some(Strategy::GraphWalk));
     match execute(
         repo,
         partition,
@@ -43,7 +54,7 @@ pub async fn run(repo: &AnyMessageRepository, cmd: &SearchSubCommand) -> Result<
         count,
         cmd.term.clone(),
         cmd.semantic,
-        cmd.link,
+        strategy,
         cmd.deduplicate,
     )
     .await
@@ -61,6 +72,9 @@ pub async fn run(repo: &AnyMessageRepository, cmd: &SearchSubCommand) -> Result<
     }
 }
 
+/// Searches the partition and instance. With a `strategy` the messages
+/// are found the way they would be for a chat request: the similar
+/// messages followed by the recent ones.
 pub async fn execute(
     repo: &AnyMessageRepository,
     partition: String,
@@ -68,38 +82,33 @@ pub async fn execute(
     count: usize,
     term: String,
     semantic: bool,
-    link: bool,
+    strategy: Option<Strategy>,
     deduplicate: bool,
 ) -> Result<Vec<Message>, Error> {
-    if semantic {
-        let embeddings = get_embeddings_for_text(&term).await?;
-        let embedding = embeddings
-            .first()
-            .map(|e| e.embedding.clone())
-            .unwrap_or_default();
+    if let Some(strategy) = strategy {
+        info!("Searching with the {} strategy", strategy);
+        let query = ContextQuery {
+            search_term: &term,
+            trace_id: "search-trace-id",
+            partition: &partition,
+            instance: &instance,
+            similar_limit: count,
+            recent_limit: get_enrichment_settings(&partition).recent_limit,
+        };
+        let context = strategy.find_context(repo, &query).await?;
+        let mut nodes: Vec<MessageNode> = context.similar.into_iter().chain(context.recent).collect();
+        load_media(&mut nodes).await;
+        let messages = nodes.iter().map(|m| m.to_message()).collect();
+        Ok(messages)
+    } else if semantic {
+        let embedding = get_embedding_for_text(&term).await?;
         let mut similar = repo
             .find_similar_messages(embedding, "search-trace-id", &partition, &instance, count)
             .await?;
         if deduplicate {
             similar = deduplicate_message_nodes(similar);
         }
-        if link {
-            let similar_pairs = repo.find_connections_between_nodes(&similar).await?;
-            similar.extend(similar_pairs);
-            let first = similar.first().cloned();
-            similar = match first {
-                Some(first) => {
-                    let nodes = repo.find_nodes_connected_to_node(&first).await?;
-                    let nodes = deduplicate_message_nodes(nodes);
-                    if nodes.len() > 2 {
-                        nodes
-                    } else {
-                        similar
-                    }
-                }
-                None => similar,
-            };
-        }
+        load_media(&mut similar).await;
         let messages: Vec<Message> = similar.iter().map(|m| m.to_message()).collect();
         Ok(messages)
     } else {
@@ -108,8 +117,8 @@ pub async fn execute(
             partition
         );
         let messages = repo.get_messages_for_partition(Some(&partition)).await?;
-        let filtered: Vec<Message> = messages
-            .iter()
+        let mut filtered: Vec<MessageNode> = messages
+            .into_iter()
             .filter(|m| {
                 m.content
                     .as_deref()
@@ -118,8 +127,8 @@ pub async fn execute(
                     .contains(&term.to_lowercase())
             })
             .take(count)
-            .map(|m| m.to_message())
             .collect();
-        Ok(filtered)
+        load_media(&mut filtered).await;
+        Ok(filtered.iter().map(|m| m.to_message()).collect())
     }
 }
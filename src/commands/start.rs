@@ -1,16 +1,46 @@
 use crate::repos::config::get_reservoir_port;
-use crate::repos::message::AnyMessageRepository;
+use crate::state::AppState;
 use anyhow::Error;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper_util::rt::TokioIo;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tracing::{error, info};
 
 use crate::handle;
 
-pub async fn start_server() -> Result<(), Error> {
+/// How often the server checks whether API keys were created or revoked.
+const API_KEYS_REFRESH: Duration = Duration::from_secs(30);
+
+/// Keeps `AppState::api_keys_exist` up to date. When Neo4j cannot be
+/// reached the last known answer is kept.
+fn spawn_api_keys_refresh(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(API_KEYS_REFRESH).await;
+            if let Err(e) = state.refresh_api_keys().await {
+                error!("Error checking for API keys: {}", e);
+            }
+        }
+    });
+}
+
+pub async fn start_server(state: AppState) -> Result<(), Error> {
+    let repo = state.message_repo();
+    if let Err(e) = repo.init_vector_index().await {
+        error!("Error creating the vector index: {}", e);
+    }
+    if let Err(e) = repo.init_lookup_indexes().await {
+        error!("Error creating the message indexes: {}", e);
+    }
+    if let Err(e) = state.refresh_api_keys().await {
+        error!("Error checking for API keys, requiring a key until Neo4j answers: {}", e);
+    }
+    spawn_api_keys_refresh(state.clone());
+    let state = Arc::new(state);
     let port = get_reservoir_port();
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
     let listener = TcpListener::bind(addr).await?;
@@ -18,9 +48,10 @@ pub async fn start_server() -> Result<(), Error> {
     loop {
         let (stream, _) = listener.accept().await?;
         let io = TokioIo::new(stream);
+        let state = state.clone();
         tokio::task::spawn(async move {
             if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(handle))
+                .serve_connection(io, service_fn(move |req| handle(req, state.clone())))
                 .await
             {
                 error!("Error serving connection: {:?}", err);
@@ -28,6 +59,6 @@ pub async fn start_server() -> Result<(), Error> {
         });
     }
 }
-pub async fn run(_repo: &AnyMessageRepository) -> Result<(), Error> {
-    start_server().await
+pub async fn run(state: AppState) -> Result<(), Error> {
+    start_server(state).await
 }
@@ -0,0 +1,21 @@
+use crate::args::{SynapsesAction, SynapsesSubCommand};
+use crate::repos::message::{AnyMessageRepository, MessageRepository};
+use anyhow::Error;
+
+pub async fn run(repo: &AnyMessageRepository, cmd: &SynapsesSubCommand) -> Result<(), Error> {
+    match cmd.action {
+        SynapsesAction::Repair => {
+            let deleted = repo.delete_cross_partition_synapses().await?;
+            println!(
+                "Deleted {} synapses between different partitions or instances",
+                deleted
+            );
+            Ok(())
+        }
+        SynapsesAction::Rebuild => {
+            let created = repo.rebuild_synapses().await?;
+            println!("Rebuilt synapses, created {}", created);
+            Ok(())
+        }
+    }
+}
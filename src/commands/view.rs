@@ -1,15 +1,16 @@
 use crate::args::ViewSubCommand;
 use crate::clients::openai::types::Message;
+use crate::models::message_node::{load_media, MessageNode};
 use crate::repos::message::{AnyMessageRepository, MessageRepository};
 use anyhow::Error;
 use tracing::{error, info};
 
-pub async fn execute(
+async fn last_nodes(
     repo: &AnyMessageRepository,
     partition: String,
     instance: String,
     count: usize,
-) -> Result<Vec<Message>, Error> {
+) -> Result<Vec<MessageNode>, Error> {
     let mut messages = repo
         .get_last_messages_for_partition_and_instance(partition, instance, count)
         .await?;
@@ -18,7 +19,17 @@ pub async fn execute(
         let b_time = b.timestamp;
         a_time.cmp(&b_time)
     });
+    Ok(messages)
+}
 
+pub async fn execute(
+    repo: &AnyMessageRepository,
+    partition: String,
+    instance: String,
+    count: usize,
+) -> Result<Vec<Message>, Error> {
+    let mut messages = last_nodes(repo, partition, instance, count).await?;
+    load_media(&mut messages).await;
     let messages: Vec<Message> = messages.iter().map(|m| m.to_message()).collect();
     Ok(messages)
 }
@@ -33,11 +44,16 @@ pub async fn run(repo: &AnyMessageRepository, view_cmd: &ViewSubCommand) -> Resu
         .clone()
         .unwrap_or_else(|| partition.clone());
 
-    match execute(repo, partition, instance, view_cmd.count).await {
+    match last_nodes(repo, partition, instance, view_cmd.count).await {
         Ok(output) => {
             // pretty print
-            for message in output {
-                println!("{}: - {}", message.role, message.content);
+            for node in output {
+                let message = node.to_message();
+                if view_cmd.ids {
+                    println!("[{}] {}: - {}", node.id, message.role, message.content);
+                } else {
+                    println!("{}: - {}", message.role, message.content);
+                }
             }
             Ok(())
         }
@@ -0,0 +1,289 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{self, HeaderValue};
+use hyper::{Response, StatusCode};
+
+use crate::clients::anthropic::types::to_error_body;
+use crate::clients::openai::types::{ErrorDetail, ErrorResponse};
+
+/// How providers word a prompt that is too long: OpenAI style APIs report the
+/// `context_length_exceeded` code, Anthropic says the prompt is too long.
+const CONTEXT_LENGTH_MARKERS: [&str; 3] = [
+    "context_length_exceeded",
+    "maximum context length",
+    "prompt is too long",
+];
+
+/// Errors that are reported back to the client. Each variant maps to an
+/// HTTP status and an OpenAI style `{"error": {...}}` body.
+#[derive(Debug)]
+pub enum ReservoirError {
+    /// The request could not be understood, e.g. invalid JSON
+    BadRequest(String),
+    /// No or an unknown Reservoir API key
+    Unauthorized(String),
+    /// The API key is not allowed to access the partition or instance
+    Forbidden(String),
+    NotFound(String),
+    /// The requested model is not in the model registry
+    ModelNotFound(String),
+    /// The provider answered with an error, its status, body and
+    /// `Retry-After` are passed on
+    Upstream {
+        status: StatusCode,
+        body: String,
+        retry_after: Option<Duration>,
+    },
+    /// The provider could not be reached or sent something unreadable
+    UpstreamUnavailable(String),
+    StorageUnavailable(String),
+    EmbeddingFailed(String),
+    ContextTooLong { tokens: usize, limit: usize },
+    Internal(String),
+}
+
+impl ReservoirError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ReservoirError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ReservoirError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ReservoirError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ReservoirError::NotFound(_) | ReservoirError::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            ReservoirError::Upstream { status, .. } => *status,
+            ReservoirError::UpstreamUnavailable(_) => StatusCode::BAD_GATEWAY,
+            ReservoirError::StorageUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ReservoirError::EmbeddingFailed(_) => StatusCode::BAD_GATEWAY,
+            ReservoirError::ContextTooLong { .. } => StatusCode::BAD_REQUEST,
+            ReservoirError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_type(&self) -> &'static str {
+        match self {
+            ReservoirError::BadRequest(_)
+            | ReservoirError::NotFound(_)
+            | ReservoirError::ModelNotFound(_)
+            | ReservoirError::ContextTooLong { .. } => "invalid_request_error",
+            ReservoirError::Unauthorized(_) => "authentication_error",
+            ReservoirError::Forbidden(_) => "permission_error",
+            ReservoirError::Upstream { .. } | ReservoirError::UpstreamUnavailable(_) => {
+                "upstream_error"
+            }
+            ReservoirError::StorageUnavailable(_) => "storage_error",
+            ReservoirError::EmbeddingFailed(_) => "embedding_error",
+            ReservoirError::Internal(_) => "server_error",
+        }
+    }
+
+    fn code(&self) -> Option<&'static str> {
+        match self {
+            ReservoirError::Unauthorized(_) => Some("invalid_api_key"),
+            ReservoirError::Forbidden(_) => Some("insufficient_permissions"),
+            ReservoirError::NotFound(_) => Some("not_found"),
+            ReservoirError::ModelNotFound(_) => Some("model_not_found"),
+            ReservoirError::ContextTooLong { .. } => Some("context_length_exceeded"),
+            ReservoirError::StorageUnavailable(_) => Some("storage_unavailable"),
+            ReservoirError::EmbeddingFailed(_) => Some("embedding_failed"),
+            _ => None,
+        }
+    }
+
+    /// True for our own `ContextTooLong` and for providers rejecting a
+    /// prompt that does not fit the model's context window.
+    pub fn is_context_length_exceeded(&self) -> bool {
+        match self {
+            ReservoirError::ContextTooLong { .. } => true,
+            ReservoirError::Upstream { status, body, .. } => {
+                (*status == StatusCode::BAD_REQUEST || *status == StatusCode::PAYLOAD_TOO_LARGE)
+                    && CONTEXT_LENGTH_MARKERS.iter().any(|marker| body.contains(marker))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn to_error_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorDetail {
+                message: self.to_string(),
+                kind: Some(self.error_type().to_string()),
+                code: self.code().map(str::to_string),
+            },
+        }
+    }
+
+    /// The body sent to the client. Error bodies from the provider are
+    /// returned untouched when they are JSON.
+    fn body(&self) -> String {
+        if let ReservoirError::Upstream { body, .. } = self {
+            if serde_json::from_str::<serde_json::Value>(body).is_ok() {
+                return body.clone();
+            }
+        }
+        serde_json::to_string(&self.to_error_response())
+            .unwrap_or_else(|_| r#"{"error":{"message":"Internal Server Error"}}"#.to_string())
+    }
+
+    pub fn into_response(self) -> Response<BoxBody<Bytes, Infallible>> {
+        let body = self.body();
+        self.into_response_with_body(body)
+    }
+
+    /// The error as the Anthropic Messages API would report it.
+    pub fn into_anthropic_response(self) -> Response<BoxBody<Bytes, Infallible>> {
+        let body = to_error_body(&self).to_string();
+        self.into_response_with_body(body)
+    }
+
+    fn into_response_with_body(self, body: String) -> Response<BoxBody<Bytes, Infallible>> {
+        let mut response = Response::new(Full::new(Bytes::from(body)).boxed());
+        *response.status_mut() = self.status();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        if let ReservoirError::Upstream {
+            retry_after: Some(retry_after),
+            ..
+        } = self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+        }
+        response
+    }
+}
+
+impl fmt::Display for ReservoirError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReservoirError::BadRequest(message)
+            | ReservoirError::Unauthorized(message)
+            | ReservoirError::Forbidden(message)
+            | ReservoirError::NotFound(message)
+            | ReservoirError::ModelNotFound(message)
+            | ReservoirError::UpstreamUnavailable(message)
+            | ReservoirError::StorageUnavailable(message)
+            | ReservoirError::EmbeddingFailed(message)
+            | ReservoirError::Internal(message) => write!(f, "{}", message),
+            ReservoirError::Upstream { status, body, .. } => {
+                write!(f, "LLM API error {}: {}", status, body)
+            }
+            ReservoirError::ContextTooLong { tokens, limit } => write!(
+                f,
+                "Your last message is too long. It contains approximately {} tokens, which exceeds the maximum limit of {}. Please shorten your message.",
+                tokens, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReservoirError {}
+
+/// True for neo4rs errors that mean Neo4j could not be reached, rather than
+/// a query that failed.
+fn is_connection_error(error: &neo4rs::Error) -> bool {
+    match error {
+        neo4rs::Error::IOError { .. }
+        | neo4rs::Error::ConnectionError
+        | neo4rs::Error::InvalidDnsName(_)
+        | neo4rs::Error::AuthenticationError(_) => true,
+        neo4rs::Error::Neo4j(e) => matches!(
+            e.kind(),
+            neo4rs::Neo4jErrorKind::Transient
+                | neo4rs::Neo4jErrorKind::Client(neo4rs::Neo4jClientErrorKind::SessionExpired)
+        ),
+        _ => false,
+    }
+}
+
+impl From<anyhow::Error> for ReservoirError {
+    fn from(error: anyhow::Error) -> Self {
+        if error.downcast_ref::<neo4rs::Error>().is_some_and(is_connection_error) {
+            return ReservoirError::StorageUnavailable(format!("Storage unavailable: {}", error));
+        }
+        match error.downcast::<ReservoirError>() {
+            Ok(error) => error,
+            Err(error) => ReservoirError::Internal(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_too_long_body() {
+        let error = ReservoirError::ContextTooLong {
+            tokens: 200,
+            limit: 100,
+        };
+        let body: serde_json::Value = serde_json::from_str(&error.body()).unwrap();
+
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["code"], "context_length_exceeded");
+    }
+
+    #[test]
+    fn test_upstream_body_is_passed_through() {
+        let upstream = r#"{"error":{"message":"Rate limit reached","type":"requests","code":"rate_limit_exceeded"}}"#;
+        let error = ReservoirError::Upstream {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            body: upstream.to_string(),
+            retry_after: None,
+        };
+
+        assert_eq!(error.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.body(), upstream);
+    }
+
+    #[test]
+    fn test_context_length_errors_from_providers() {
+        let upstream = |status, body: &str| ReservoirError::Upstream {
+            status,
+            body: body.to_string(),
+            retry_after: None,
+        };
+
+        assert!(upstream(
+            StatusCode::BAD_REQUEST,
+            r#"{"error":{"code":"context_length_exceeded"}}"#
+        )
+        .is_context_length_exceeded());
+        assert!(upstream(
+            StatusCode::BAD_REQUEST,
+            r#"{"type":"error","error":{"message":"prompt is too long: 210000 tokens > 200000 maximum"}}"#
+        )
+        .is_context_length_exceeded());
+        assert!(!upstream(StatusCode::BAD_REQUEST, "invalid temperature").is_context_length_exceeded());
+        assert!(!upstream(StatusCode::BAD_GATEWAY, "context_length_exceeded").is_context_length_exceeded());
+    }
+
+    #[test]
+    fn test_from_anyhow_keeps_typed_errors() {
+        let error: anyhow::Error = ReservoirError::BadRequest("bad".to_string()).into();
+        assert!(matches!(
+            ReservoirError::from(error),
+            ReservoirError::BadRequest(_)
+        ));
+
+        let error = ReservoirError::from(anyhow::anyhow!("boom"));
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_only_connection_errors_are_storage_unavailable() {
+        let error = ReservoirError::from(anyhow::Error::from(neo4rs::Error::ConnectionError));
+        assert!(matches!(error, ReservoirError::StorageUnavailable(_)));
+
+        let error = ReservoirError::from(anyhow::Error::from(neo4rs::Error::ConversionError));
+        assert!(matches!(error, ReservoirError::Internal(_)));
+    }
+}
@@ -0,0 +1,131 @@
+use hyper::header::{self, HeaderMap};
+
+use crate::errors::ReservoirError;
+use crate::models::api_key::{hash_api_key, ApiKey, Permission, API_KEY_PREFIX};
+use crate::repos::api_key::ApiKeyRepository;
+use crate::state::AppState;
+
+/// Who is making a request. Access is open until the first Reservoir API
+/// key has been created, after that every request needs a valid key.
+pub enum Access {
+    Open,
+    Key(ApiKey),
+}
+
+impl Access {
+    pub fn check(
+        &self,
+        permission: Permission,
+        partition: &str,
+        instance: &str,
+    ) -> Result<(), ReservoirError> {
+        match self {
+            Access::Open => Ok(()),
+            Access::Key(key) if key.allows(permission, partition, instance) => Ok(()),
+            Access::Key(key) => Err(ReservoirError::Forbidden(format!(
+                "API key '{}' does not have {} access to partition '{}' instance '{}'",
+                key.name,
+                permission.as_str(),
+                partition,
+                instance
+            ))),
+        }
+    }
+
+    /// An enriched chat request puts stored messages of the partition and
+    /// instance into the prompt, so it needs read or search access to them.
+    /// Write access is only needed when the request is stored.
+    pub fn check_chat(
+        &self,
+        enrich: bool,
+        store: bool,
+        partition: &str,
+        instance: &str,
+    ) -> Result<(), ReservoirError> {
+        if enrich {
+            self.check(Permission::Read, partition, instance)
+                .or_else(|_| self.check(Permission::Search, partition, instance))?;
+        }
+        if store {
+            self.check(Permission::Write, partition, instance)?;
+        }
+        Ok(())
+    }
+}
+
+/// The key sent by the client, either as a bearer token or, like the
+/// Anthropic SDKs do, in `x-api-key`.
+fn get_client_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()))
+        .map(str::trim)
+}
+
+/// Validates the Reservoir key of a request. This happens before anything
+/// is routed; the upstream request is always sent with the provider key
+/// from the model configuration, never with the key of the client. Whether
+/// Reservoir is still open is kept in the state, so requests without a key
+/// do not touch Neo4j.
+pub async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Access, ReservoirError> {
+    match get_client_key(headers).filter(|k| k.starts_with(API_KEY_PREFIX)) {
+        Some(client_key) => {
+            let repo = state.api_key_repo();
+            match repo.find_api_key_by_hash(&hash_api_key(client_key)).await? {
+                Some(key) => {
+                    state.mark_api_keys_exist();
+                    Ok(Access::Key(key))
+                }
+                None => Err(ReservoirError::Unauthorized(
+                    "Invalid Reservoir API key".to_string(),
+                )),
+            }
+        }
+        None => {
+            if state.api_keys_exist() {
+                Err(ReservoirError::Unauthorized(
+                    "A Reservoir API key is required".to_string(),
+                ))
+            } else {
+                Ok(Access::Open)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(permissions: Vec<Permission>) -> Access {
+        let (key, _) = ApiKey::generate(
+            "test".to_string(),
+            vec!["work".to_string()],
+            vec!["*".to_string()],
+            permissions,
+        );
+        Access::Key(key)
+    }
+
+    #[test]
+    fn test_write_only_key_cannot_enrich() {
+        let access = key(vec![Permission::Write]);
+
+        assert!(access.check_chat(true, true, "work", "work").is_err());
+        assert!(access.check_chat(false, true, "work", "work").is_ok());
+        assert!(access.check_chat(false, true, "home", "home").is_err());
+    }
+
+    #[test]
+    fn test_chat_permissions() {
+        let search = key(vec![Permission::Search]);
+        assert!(search.check_chat(true, false, "work", "work").is_ok());
+        assert!(search.check_chat(true, true, "work", "work").is_err());
+
+        let both = key(vec![Permission::Read, Permission::Write]);
+        assert!(both.check_chat(true, true, "work", "work").is_ok());
+        assert!(Access::Open.check_chat(true, true, "work", "work").is_ok());
+    }
+}
@@ -1,185 +1,437 @@
 use anyhow::Error;
 
-use crate::clients::openai::chat_completions::get_completion_message;
 use crate::clients::openai::model_info::ModelInfo;
-use crate::clients::openai::types::{
-    enrich_chat_request, ChatRequest, ChatResponse, Choice, Message,
+use crate::clients::openai::types::{enrich_chat_request, ChatRequest, Message};
+use crate::clients::registry::get_model_registry;
+use crate::clients::router::{route_model, RouteContext};
+use crate::clients::provider::{
+    forward_completion_stream, get_completion_message, get_completion_stream, missing_capability,
 };
-use crate::models::message_node::MessageNode;
+use crate::errors::ReservoirError;
+use crate::handler::auth::Access;
+use crate::handler::options::RequestOptions;
+use crate::models::message_node::{load_media, MessageNode};
+use crate::repos::config::{get_enrichment_settings, get_route_configs};
 use crate::repos::message::Neo4jMessageRepository;
+use crate::services::context::{ContextQuery, ContextStrategy};
 use crate::services::ChatRequestService;
-use crate::utils::{count_single_message_tokens, deduplicate_message_nodes, get_last_message_in_chat_request, truncate_messages_if_needed};
+use crate::state::AppState;
+use crate::utils::{count_chat_tokens, count_single_message_tokens, Tokenizer, fit_token_budget, get_last_message_in_chat_request, truncate_messages_if_needed};
 use crate::{
-    clients::openai::embeddings::get_embeddings_for_text, repos::message::MessageRepository,
+    clients::openai::embeddings::get_embedding_for_text, repos::message::MessageRepository,
 };
 use bytes::Bytes;
+use chrono::Timelike;
+use http_body_util::channel::Channel;
 use uuid::Uuid;
 
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-const SIMILAR_MESSAGES_LIMIT: usize = 7;
-const LAST_MESSAGES_LIMIT: usize = 15;
+const STREAM_BUFFER_SIZE: usize = 32;
+/// Response header naming the requested model when another one answered
+pub const MODEL_SWITCHED_HEADER: &str = "x-reservoir-model-switched";
 
+/// The response to a chat completion, either the full JSON body or a
+/// channel of server sent events that is filled as the upstream streams.
+pub enum ChatCompletionBody {
+    Complete(Bytes),
+    Stream(Channel<Bytes>),
+}
 
+pub struct ChatCompletion {
+    pub body: ChatCompletionBody,
+    /// The model the client asked for, when a larger context model was
+    /// used to fit the prompt
+    pub switched_from: Option<String>,
+}
 
-pub async fn is_last_message_too_big(last_message: &Message, model: &ModelInfo) -> Option<Bytes> {
+pub fn check_last_message_size(last_message: &Message, model: &ModelInfo) -> Result<(), ReservoirError> {
     let input_token_limit = model.input_tokens;
-    let last_message_tokens = count_single_message_tokens(last_message);
+    let last_message_tokens = count_single_message_tokens(last_message, model.tokenizer);
     if last_message_tokens > input_token_limit {
         info!(
             "Last message token count ({}) exceeds limit ({}), returning error response.",
             last_message_tokens, input_token_limit
         );
-
-        let error_content = format!(
-                "Your last message is too long. It contains approximately {} tokens, which exceeds the maximum limit of {}. Please shorten your message.",
-                last_message_tokens, input_token_limit
-            );
-        let error_message = Message {
-            role: "assistant".to_string(),
-            content: error_content,
-        };
-
-        let error_choice = Choice {
-            index: 0,
-            message: error_message,
-            finish_reason: "length".to_string(), // Indicate truncation due to length
-        };
-        let error_response = ChatResponse {
-            id: None,
-            object: None,
-            created: None,
-            model: None,
-            choices: vec![error_choice],
-            usage: None,
-        };
-
-        // Serialize and return the error response
-        let response_bytes = serde_json::to_vec(&error_response).unwrap();
-        return Some(Bytes::from(response_bytes));
+        Err(ReservoirError::ContextTooLong {
+            tokens: last_message_tokens,
+            limit: input_token_limit,
+        })
     } else {
         info!(
             "Last message token count ({}) is within limit ({}).",
             last_message_tokens, input_token_limit
         );
-        return None;
+        Ok(())
     }
 }
-pub async fn handle_with_partition(
+
+/// Replaces a logical model name such as `fast` with the model its route
+/// picks for this request.
+fn route_request(chat_request: &mut ChatRequest, partition: &str, instance: &str) {
+    let routes = get_route_configs();
+    if !routes.iter().any(|r| r.name == chat_request.model) {
+        return;
+    }
+    let context = RouteContext {
+        partition,
+        instance,
+        prompt_tokens: count_chat_tokens(&chat_request.messages, Tokenizer::default()),
+        has_images: chat_request.messages.iter().any(Message::has_media),
+        hour: chrono::Local::now().hour(),
+    };
+    if let Some(model) = route_model(routes, &chat_request.model, &context) {
+        chat_request.model = model;
+    }
+}
+
+/// Rejects requests that need something the model is not declared to do.
+fn check_capabilities(chat_request: &ChatRequest, model: &ModelInfo) -> Result<(), ReservoirError> {
+    match missing_capability(chat_request, model) {
+        Some(capability) => Err(ReservoirError::BadRequest(format!(
+            "The model '{}' does not support {}",
+            chat_request.model, capability
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Finds the messages to inject for a request with the strategy the
+/// request asks for, or else the one of its partition. The similar and the
+/// recent messages are each cut down to the token budget of the partition.
+async fn find_context(
+    message_repo: &Neo4jMessageRepository,
+    search_term: &str,
+    trace_id: &str,
     partition: &str,
     instance: &str,
+    options: &RequestOptions,
+    tokenizer: Tokenizer,
+) -> Result<(Vec<MessageNode>, Vec<MessageNode>), Error> {
+    let settings = get_enrichment_settings(partition);
+    let strategy = options.strategy.unwrap_or(settings.strategy);
+    info!("Finding context with the {} strategy", strategy);
+
+    let query = ContextQuery {
+        search_term,
+        trace_id,
+        partition,
+        instance,
+        similar_limit: options.similar_limit.unwrap_or(settings.similar_limit),
+        recent_limit: options.recent_limit.unwrap_or(settings.recent_limit),
+    };
+    let context = strategy.find_context(message_repo, &query).await?;
+    let mut similar = fit_token_budget(context.similar, settings.semantic_token_budget, tokenizer);
+    let mut recent = fit_token_budget(context.recent, settings.recent_token_budget, tokenizer);
+    load_media(&mut similar).await;
+    load_media(&mut recent).await;
+    Ok((similar, recent))
+}
+
+pub async fn handle_with_partition(
+    state: &AppState,
+    path_partition: &str,
+    path_instance: Option<&str>,
+    header_options: RequestOptions,
+    access: &Access,
     whole_body: Bytes,
-) -> Result<Bytes, Error> {
+) -> Result<ChatCompletion, Error> {
     let json_string = String::from_utf8_lossy(&whole_body).to_string();
-    let mut chat_request_model = ChatRequest::from_json(json_string.as_str()).expect("Valid JSON");
-    let model = ModelInfo::new(chat_request_model.model.clone());
+    let chat_request_model = ChatRequest::from_json(json_string.as_str())
+        .map_err(|e| ReservoirError::BadRequest(format!("Invalid chat request: {}", e)))?;
+    handle_chat_request(
+        state,
+        path_partition,
+        path_instance,
+        header_options,
+        access,
+        chat_request_model,
+    )
+    .await
+}
+
+/// Enriches, stores and forwards a chat request. Used by every endpoint
+/// that accepts chat, whatever format the request arrived in.
+pub async fn handle_chat_request(
+    state: &AppState,
+    path_partition: &str,
+    path_instance: Option<&str>,
+    header_options: RequestOptions,
+    access: &Access,
+    mut chat_request_model: ChatRequest,
+) -> Result<ChatCompletion, Error> {
+    let options = header_options.merge(RequestOptions::take_from_request(&mut chat_request_model)?);
+    let (partition, instance) = options.resolve_scope(path_partition, path_instance);
+    let (partition, instance) = (partition.as_str(), instance.as_str());
+    info!("Partition: {}, Instance: {}", partition, instance);
+    access.check_chat(options.should_enrich(), options.should_store(), partition, instance)?;
+
+    route_request(&mut chat_request_model, partition, instance);
+    let mut chain = get_model_registry().resolve_chain(&chat_request_model.model)?;
+    check_capabilities(&chat_request_model, &chain[0])?;
+    let mut switched_from = None;
 
     let trace_id = Uuid::new_v4().to_string();
-    let message_repo = Neo4jMessageRepository::default();
-    let service = ChatRequestService::new(&message_repo);
+    let message_repo = state.message_repo();
 
     let last_message = chat_request_model
         .messages
         .last()
-        .ok_or_else(|| anyhow::anyhow!("There are no messages in the request"))?;
+        .ok_or_else(|| ReservoirError::BadRequest("There are no messages in the request".to_string()))?;
 
-    let too_big = is_last_message_too_big(last_message, &model).await;
-    if let Some(bytes) = too_big {
-        return Ok(bytes);
+    if let Err(e) = check_last_message_size(last_message, &chain[0]) {
+        let larger = larger_context_chain(&chain[0], &chat_request_model).ok_or(e)?;
+        check_last_message_size(last_message, &larger[0])?;
+        switched_from = Some(chat_request_model.model.clone());
+        chain = larger;
     }
 
     let search_term = last_message.content.as_str();
-    get_last_message_in_chat_request(&chat_request_model)?;
-
-    info!("Using search term: {}", search_term);
-    let embeddings = get_embeddings_for_text(search_term)
-        .await?
-        .first()
-        .unwrap()
-        .embedding
-        .clone();
+    get_last_message_in_chat_request(&chat_request_model)
+        .map_err(|e| ReservoirError::BadRequest(e.to_string()))?;
 
-    let mut similar = if !embeddings.is_empty() {
-        service.find_similar_messages(
-            embeddings,
+    let (similar, last_messages) = if options.should_enrich() {
+        find_context(
+            &message_repo,
+            search_term,
             trace_id.as_str(),
             partition,
             instance,
-            SIMILAR_MESSAGES_LIMIT,
+            &options,
+            chain[0].tokenizer,
         )
-        .await
-        .unwrap_or_else(|e| {
-            error!("Error finding similar messages: {}", e);
-            Vec::new()
-        })
+        .await?
+    } else {
+        info!("Enrichment disabled for this request");
+        (Vec::new(), Vec::new())
+    };
+
+    let store = options.should_store();
+    let parent_hash = if store {
+        ChatRequestService::new(&message_repo)
+            .save_chat_request(&chat_request_model, trace_id.as_str(), partition, instance)
+            .await?
+    } else {
+        info!("Storage disabled for this request");
+        None
+    };
+
+    let untruncated_request = if options.should_enrich() {
+        enrich_chat_request(similar, last_messages, &chat_request_model)
     } else {
-        Vec::new()
+        chat_request_model.clone()
     };
-    similar = deduplicate_message_nodes(similar);
-
-    let similar_pairs = message_repo.find_connections_between_nodes(&similar).await?;
-    similar.extend(similar_pairs);
-    let first = similar.first().clone();
-    let similar = match first {
-        Some(first) => {
-            let nodes = message_repo.find_nodes_connected_to_node(first).await?;
-            let nodes = deduplicate_message_nodes(nodes);
-
-            if nodes.len() > 2 {
-                nodes
-            } else {
-                similar
+    if switched_from.is_none()
+        && count_chat_tokens(&untruncated_request.messages, chain[0].tokenizer) > chain[0].input_tokens
+    {
+        if let Some(larger) = larger_context_chain(&chain[0], &chat_request_model) {
+            switched_from = Some(chat_request_model.model.clone());
+            chain = larger;
+        }
+    }
+    let enriched_chat_request = truncated_for(&untruncated_request, &chain[0]);
+
+    if chat_request_model.is_stream() {
+        let result = get_completion_stream(&chain, &enriched_chat_request, trace_id.as_str())
+            .await
+            .map(|(upstream, served)| (upstream, served.name.clone()));
+        let (upstream, model_name) = match result {
+            Err(e) if switched_from.is_none() && is_context_length_error(&e) => {
+                let Some(larger) = larger_context_chain(&chain[0], &chat_request_model) else {
+                    return Err(e);
+                };
+                switched_from = Some(chat_request_model.model.clone());
+                let retry_request = truncated_for(&untruncated_request, &larger[0]);
+                let (upstream, served) =
+                    get_completion_stream(&larger, &retry_request, trace_id.as_str()).await?;
+                (upstream, served.name.clone())
             }
+            result => result?,
+        };
+        let (sender, body) = Channel::new(STREAM_BUFFER_SIZE);
+        let partition = partition.to_string();
+        let instance = instance.to_string();
+        let origin = ResponseOrigin {
+            model: model_name,
+            switched_from: switched_from.clone(),
+            parent_hash,
+        };
+
+        // The answer is stored from a separate task so that a client
+        // disconnecting halfway through does not leave the request without
+        // its response in the graph.
+        tokio::spawn(async move {
+            let message = forward_completion_stream(upstream, sender).await;
+            if !store {
+                return;
+            }
+            if let Err(e) = save_assistant_message(
+                &origin,
+                &message_repo,
+                &message,
+                trace_id.as_str(),
+                partition.as_str(),
+                instance.as_str(),
+            )
+            .await
+            {
+                error!("Error saving streamed response: {}", e);
+            }
+            spawn_connect_synapses(&message_repo, &trace_id, &partition, &instance);
+        });
+        return Ok(ChatCompletion {
+            body: ChatCompletionBody::Stream(body),
+            switched_from,
+        });
+    }
+
+    let result = get_completion_message(&chain, &enriched_chat_request, trace_id.as_str())
+        .await
+        .map(|(response, served)| (response, served.name.clone()));
+    let (chat_response, model_name) = match result {
+        Err(e) if switched_from.is_none() && is_context_length_error(&e) => {
+            let Some(larger) = larger_context_chain(&chain[0], &chat_request_model) else {
+                return Err(e);
+            };
+            switched_from = Some(chat_request_model.model.clone());
+            let retry_request = truncated_for(&untruncated_request, &larger[0]);
+            let (response, served) =
+                get_completion_message(&larger, &retry_request, trace_id.as_str()).await?;
+            (response, served.name.clone())
         }
-        None => similar,
+        result => result?,
     };
 
-    let last_messages = message_repo
-        .get_last_messages_for_partition_and_instance(
-            partition.to_string(),
-            instance.to_string(),
-            LAST_MESSAGES_LIMIT,
+    let message = chat_response
+        .choices
+        .first()
+        .ok_or_else(|| {
+            ReservoirError::UpstreamUnavailable("LLM API returned no choices".to_string())
+        })?
+        .message
+        .clone();
+    // The client still gets its answer if it could not be stored
+    if store {
+        let origin = ResponseOrigin {
+            model: model_name,
+            switched_from: switched_from.clone(),
+            parent_hash,
+        };
+        if let Err(e) = save_assistant_message(
+            &origin,
+            &message_repo,
+            &message,
+            trace_id.as_str(),
+            partition,
+            instance,
         )
         .await
-        .unwrap_or_else(|e| {
-            error!("Error finding last messages: {}", e);
-            Vec::new() 
-        });
-    service.save_chat_request(&chat_request_model, trace_id.as_str(), partition, instance)
-        .await
-        .expect("Could not save the request");
+        {
+            error!("Error saving response: {}", e);
+        }
+        spawn_connect_synapses(&message_repo, trace_id.as_str(), partition, instance);
+    }
 
-    let mut enriched_chat_request =
-        enrich_chat_request(similar, last_messages, &mut chat_request_model);
-    truncate_messages_if_needed(&mut enriched_chat_request.messages, model.input_tokens);
+    let response_text = serde_json::to_string(&chat_response)?;
+    Ok(ChatCompletion {
+        body: ChatCompletionBody::Complete(Bytes::from(response_text)),
+        switched_from,
+    })
+}
 
-    let chat_response = get_completion_message(&model, &enriched_chat_request)
-        .await
-        .expect("Failed to get completion message");
+/// The request with its oldest messages dropped until it fits the context
+/// window of `model`.
+fn truncated_for(request: &ChatRequest, model: &ModelInfo) -> ChatRequest {
+    let mut request = request.clone();
+    truncate_messages_if_needed(&mut request.messages, model.input_tokens, model.tokenizer);
+    request
+}
 
-    let message_node = chat_response.choices.first().unwrap().message.clone();
-    let embedding = get_embeddings_for_text(message_node.content.as_str())
-        .await?
-        .first()
-        .unwrap()
-        .embedding
-        .clone();
-    let message_node = MessageNode::from_message(
-        &message_node,
-        trace_id.as_str(),
+/// The chain of the larger context model configured for `model`, when
+/// there is one with a bigger window that can serve the request.
+fn larger_context_chain(model: &ModelInfo, chat_request: &ChatRequest) -> Option<Vec<ModelInfo>> {
+    let name = model.larger_context.as_deref()?;
+    let chain = match get_model_registry().resolve_chain(name) {
+        Ok(chain) => chain,
+        Err(e) => {
+            warn!("Larger context model {} of {} is not usable: {}", name, model.name, e);
+            return None;
+        }
+    };
+    if chain[0].input_tokens <= model.input_tokens {
+        warn!("Larger context model {} of {} has no larger context window", name, model.name);
+        return None;
+    }
+    if let Err(e) = check_capabilities(chat_request, &chain[0]) {
+        warn!("Larger context model {} of {} cannot serve the request: {}", name, model.name, e);
+        return None;
+    }
+    info!("Switching from model {} to {} to fit the prompt", model.name, name);
+    Some(chain)
+}
+
+fn is_context_length_error(error: &Error) -> bool {
+    error
+        .downcast_ref::<ReservoirError>()
+        .is_some_and(ReservoirError::is_context_length_exceeded)
+}
+
+/// Where an answer came from: the model that gave it and the message it
+/// follows in the conversation.
+struct ResponseOrigin {
+    model: String,
+    switched_from: Option<String>,
+    parent_hash: Option<String>,
+}
+
+async fn save_assistant_message(
+    origin: &ResponseOrigin,
+    message_repo: &Neo4jMessageRepository,
+    message: &Message,
+    trace_id: &str,
+    partition: &str,
+    instance: &str,
+) -> Result<(), Error> {
+    let text = message.searchable_text();
+    if text.is_empty() {
+        info!("Assistant message for trace {} is empty, not storing it", trace_id);
+        return Ok(());
+    }
+
+    let embedding = get_embedding_for_text(text.as_str()).await?;
+    let mut message_node = MessageNode::from_message(
+        message,
+        trace_id,
         partition,
         instance,
         embedding,
     );
-    message_repo.save_message_node(&message_node)
-        .await
-        .expect("Failed to save message node");
-
-    message_repo.connect_synapses()
-        .await
-        .expect("Failed to connect synapses");
+    message_node.model = Some(origin.model.clone());
+    message_node.switched_from = origin.switched_from.clone();
+    message_node.chain_hash = Some(message.chain_hash(origin.parent_hash.as_deref()));
+    message_node.parent_hash = origin.parent_hash.clone();
+    message_repo.save_message_node(&message_node).await?;
+    Ok(())
+}
 
-    let response_text =
-        serde_json::to_string(&chat_response).expect("Failed to serialize chat response");
-    Ok(Bytes::from(response_text))
+/// Links the messages stored for the trace to the conversation in the
+/// background, the client does not wait for its synapses.
+pub fn spawn_connect_synapses(
+    message_repo: &Neo4jMessageRepository,
+    trace_id: &str,
+    partition: &str,
+    instance: &str,
+) {
+    let message_repo = message_repo.clone();
+    let trace_id = trace_id.to_string();
+    let partition = partition.to_string();
+    let instance = instance.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = message_repo
+            .connect_synapses(&trace_id, &partition, &instance)
+            .await
+        {
+            error!("Error connecting synapses for trace {}: {}", trace_id, e);
+        }
+    });
 }
@@ -0,0 +1,80 @@
+use anyhow::Error;
+use bytes::Bytes;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::clients::openai::embeddings::{
+    get_embedding_for_text, send_embeddings_request, EmbeddingRequest, EmbeddingResponse,
+    EMBEDDING_MODEL,
+};
+use crate::clients::openai::types::Message;
+use crate::errors::ReservoirError;
+use crate::handler::auth::Access;
+use crate::handler::completions::spawn_connect_synapses;
+use crate::handler::options::RequestOptions;
+use crate::models::api_key::Permission;
+use crate::models::message_node::MessageNode;
+use crate::repos::message::MessageRepository;
+use crate::state::AppState;
+
+/// Proxies an OpenAI style embeddings request. Unlike chat, the texts are
+/// only stored when asked to with `X-Reservoir-Store: on` or
+/// `"reservoir": {"store": true}`.
+pub async fn handle_embeddings(
+    state: &AppState,
+    path_partition: &str,
+    path_instance: Option<&str>,
+    header_options: RequestOptions,
+    access: &Access,
+    whole_body: Bytes,
+) -> Result<Bytes, Error> {
+    let mut request: EmbeddingRequest = serde_json::from_slice(&whole_body)
+        .map_err(|e| ReservoirError::BadRequest(format!("Invalid embeddings request: {}", e)))?;
+    let options = header_options.merge(RequestOptions::take_from_extra(&mut request.extra)?);
+    let (partition, instance) = options.resolve_scope(path_partition, path_instance);
+    let store = options.store.unwrap_or(false);
+    // Only storing the texts writes to the partition, embedding them is
+    // what a search does
+    let permission = if store { Permission::Write } else { Permission::Search };
+    access.check(permission, &partition, &instance)?;
+
+    // The answer is passed on as it is, so every `encoding_format` works
+    let body = send_embeddings_request(&request).await?;
+
+    if store {
+        let texts = request.input.texts().ok_or_else(|| {
+            ReservoirError::BadRequest("Only text input can be stored".to_string())
+        })?;
+        let repo = state.message_repo();
+        let trace_id = Uuid::new_v4().to_string();
+        // The returned vectors can only be reused when they fit the vector index
+        let reuse = request.model == EMBEDDING_MODEL && !request.extra.contains_key("dimensions");
+        let response: EmbeddingResponse = serde_json::from_slice(&body).map_err(|e| {
+            ReservoirError::EmbeddingFailed(format!("Invalid embeddings response: {}", e))
+        })?;
+
+        for (index, text) in texts.iter().enumerate() {
+            let embedding = match response.data.iter().find(|e| e.index as usize == index) {
+                Some(e) if reuse => e.embedding.values()?,
+                _ => get_embedding_for_text(text).await?,
+            };
+            let message = Message {
+                role: "user".to_string(),
+                content: text.to_string(),
+                ..Default::default()
+            };
+            let node = MessageNode::from_message(&message, &trace_id, &partition, &instance, embedding);
+            repo.save_message_node(&node).await?;
+        }
+        spawn_connect_synapses(&repo, &trace_id, &partition, &instance);
+        info!(
+            "Stored {} embedded texts in partition {} instance {} with trace {}",
+            texts.len(),
+            partition,
+            instance,
+            trace_id
+        );
+    }
+
+    Ok(body)
+}
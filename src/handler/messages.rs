@@ -0,0 +1,64 @@
+use anyhow::Error;
+use bytes::Bytes;
+use tracing::info;
+
+use crate::clients::anthropic::types::{MessagesRequest, MessagesResponse};
+use crate::clients::openai::types::ChatResponse;
+use crate::errors::ReservoirError;
+use crate::handler::auth::Access;
+use crate::handler::completions::{handle_chat_request, ChatCompletionBody};
+use crate::handler::options::RequestOptions;
+use crate::state::AppState;
+
+/// The answer to a Messages API request, either a JSON body or the same
+/// answer as server sent events.
+pub enum MessagesBody {
+    Json(Bytes),
+    EventStream(Bytes),
+}
+
+/// Accepts a request in Anthropic's Messages API format and runs it through
+/// the same enrichment and storage as a chat completion. The upstream is
+/// always called without streaming; a stream requested by the client is
+/// replayed from the finished answer. Returned with the model the client
+/// asked for, when a larger context model answered instead.
+pub async fn handle_messages(
+    state: &AppState,
+    path_partition: &str,
+    path_instance: Option<&str>,
+    header_options: RequestOptions,
+    access: &Access,
+    whole_body: Bytes,
+) -> Result<(MessagesBody, Option<String>), Error> {
+    let request: MessagesRequest = serde_json::from_slice(&whole_body)
+        .map_err(|e| ReservoirError::BadRequest(format!("Invalid messages request: {}", e)))?;
+    let stream = request.is_stream();
+    let model = request.model.clone();
+    info!("Messages request for model {}", model);
+
+    let chat_request = request.into_chat_request();
+    let completion = handle_chat_request(
+        state,
+        path_partition,
+        path_instance,
+        header_options,
+        access,
+        chat_request,
+    )
+    .await?;
+    let ChatCompletionBody::Complete(bytes) = completion.body else {
+        return Err(ReservoirError::Internal("Unexpected stream for a messages request".to_string()).into());
+    };
+
+    let chat_response: ChatResponse = serde_json::from_slice(&bytes).map_err(|e| {
+        ReservoirError::UpstreamUnavailable(format!("Invalid response from the model: {}", e))
+    })?;
+    let response = MessagesResponse::from_chat_response(chat_response, &model)?;
+
+    let body = if stream {
+        MessagesBody::EventStream(Bytes::from(response.to_sse_events()))
+    } else {
+        MessagesBody::Json(Bytes::from(serde_json::to_string(&response)?))
+    };
+    Ok((body, completion.switched_from))
+}
@@ -1 +1,6 @@
+pub mod auth;
 pub mod completions;
+pub mod embeddings;
+pub mod messages;
+pub mod models;
+pub mod options;
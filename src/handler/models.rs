@@ -0,0 +1,59 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use tracing::{info, warn};
+
+use crate::clients::openai::models::{list_models, ModelList, ModelObject};
+use crate::clients::registry::get_model_registry;
+use crate::repos::config::get_route_configs;
+
+/// How long a model list is served before the upstreams are asked again.
+const MODELS_CACHE_TTL: Duration = Duration::from_secs(300);
+
+static MODELS_CACHE: Lazy<Mutex<Option<(Instant, ModelList)>>> = Lazy::new(|| Mutex::new(None));
+
+fn cached_models(now: Instant) -> Option<ModelList> {
+    let cache = MODELS_CACHE.lock().ok()?;
+    match cache.as_ref() {
+        Some((fetched_at, list)) if now.duration_since(*fetched_at) < MODELS_CACHE_TTL => {
+            Some(list.clone())
+        }
+        _ => None,
+    }
+}
+
+/// Lists the routes and models in the registry followed by the ones the configured
+/// upstreams report that match a pattern in the registry. Upstreams that can not be reached are
+/// left out rather than failing the request.
+pub async fn handle_models() -> ModelList {
+    if let Some(list) = cached_models(Instant::now()) {
+        return list;
+    }
+
+    let registry = get_model_registry();
+    let mut models: Vec<ModelObject> = get_route_configs()
+        .iter()
+        .map(|route| ModelObject::new(&route.name, "reservoir"))
+        .collect();
+    models.extend(
+        registry
+            .model_names()
+            .iter()
+            .map(|(id, owned_by)| ModelObject::new(id, owned_by)),
+    );
+    for source in registry.model_sources() {
+        match list_models(&source).await {
+            // Only models that can be requested are listed
+            Ok(found) => models.extend(found.into_iter().filter(|m| registry.is_known(&m.id))),
+            Err(e) => warn!("Could not list models from {}: {}", source.url(), e),
+        }
+    }
+    let list = ModelList::new(models);
+    info!("Listing {} models", list.data.len());
+
+    if let Ok(mut cache) = MODELS_CACHE.lock() {
+        *cache = Some((Instant::now(), list.clone()));
+    }
+    list
+}
@@ -0,0 +1,204 @@
+use hyper::header::HeaderMap;
+use serde::Deserialize;
+
+use crate::clients::openai::types::{ChatRequest, ExtraFields};
+use crate::errors::ReservoirError;
+use crate::models::strategy::Strategy;
+
+const ENRICH_HEADER: &str = "x-reservoir-enrich";
+const STORE_HEADER: &str = "x-reservoir-store";
+const SIMILAR_LIMIT_HEADER: &str = "x-reservoir-similar-limit";
+const RECENT_LIMIT_HEADER: &str = "x-reservoir-recent-limit";
+const STRATEGY_HEADER: &str = "x-reservoir-strategy";
+const PARTITION_HEADER: &str = "x-reservoir-partition";
+const INSTANCE_HEADER: &str = "x-reservoir-instance";
+
+/// Name of the optional object in the request body that carries the same
+/// options as the headers. It is removed before the request is forwarded.
+const BODY_FIELD: &str = "reservoir";
+
+/// Per request overrides of how Reservoir enriches and stores a chat
+/// request. Unset options fall back to the defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestOptions {
+    /// Inject past messages into the request
+    pub enrich: Option<bool>,
+    /// Store the request and response in the graph
+    pub store: Option<bool>,
+    pub similar_limit: Option<usize>,
+    pub recent_limit: Option<usize>,
+    /// How the injected messages are found, instead of the strategy of the
+    /// partition
+    pub strategy: Option<Strategy>,
+    pub partition: Option<String>,
+    pub instance: Option<String>,
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "on" | "true" | "1" | "yes" => Some(true),
+        "off" | "false" | "0" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+impl RequestOptions {
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, ReservoirError> {
+        let get = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let flag = |name: &str| -> Result<Option<bool>, ReservoirError> {
+            get(name)
+                .map(|v| {
+                    parse_flag(v).ok_or_else(|| {
+                        ReservoirError::BadRequest(format!("Invalid value '{}' for {}", v, name))
+                    })
+                })
+                .transpose()
+        };
+        let number = |name: &str| -> Result<Option<usize>, ReservoirError> {
+            get(name)
+                .map(|v| {
+                    v.trim().parse().map_err(|_| {
+                        ReservoirError::BadRequest(format!("Invalid value '{}' for {}", v, name))
+                    })
+                })
+                .transpose()
+        };
+
+        let strategy = get(STRATEGY_HEADER)
+            .map(|v| {
+                Strategy::parse(v).ok_or_else(|| {
+                    ReservoirError::BadRequest(format!("Invalid value '{}' for {}", v, STRATEGY_HEADER))
+                })
+            })
+            .transpose()?;
+
+        Ok(RequestOptions {
+            enrich: flag(ENRICH_HEADER)?,
+            store: flag(STORE_HEADER)?,
+            similar_limit: number(SIMILAR_LIMIT_HEADER)?,
+            recent_limit: number(RECENT_LIMIT_HEADER)?,
+            strategy,
+            partition: get(PARTITION_HEADER).map(str::to_string),
+            instance: get(INSTANCE_HEADER).map(str::to_string),
+        })
+    }
+
+    /// Takes the `reservoir` object out of the request body, so it is not
+    /// sent to the provider.
+    pub fn take_from_request(chat_request: &mut ChatRequest) -> Result<Self, ReservoirError> {
+        Self::take_from_extra(&mut chat_request.extra)
+    }
+
+    /// Takes the `reservoir` object out of the unmodelled fields of a body.
+    pub fn take_from_extra(extra: &mut ExtraFields) -> Result<Self, ReservoirError> {
+        match extra.remove(BODY_FIELD) {
+            Some(value) => serde_json::from_value(value).map_err(|e| {
+                ReservoirError::BadRequest(format!("Invalid '{}' options: {}", BODY_FIELD, e))
+            }),
+            None => Ok(RequestOptions::default()),
+        }
+    }
+
+    /// Combines two sets of options, the ones given in `other` win.
+    pub fn merge(self, other: RequestOptions) -> Self {
+        RequestOptions {
+            enrich: other.enrich.or(self.enrich),
+            store: other.store.or(self.store),
+            similar_limit: other.similar_limit.or(self.similar_limit),
+            recent_limit: other.recent_limit.or(self.recent_limit),
+            strategy: other.strategy.or(self.strategy),
+            partition: other.partition.or(self.partition),
+            instance: other.instance.or(self.instance),
+        }
+    }
+
+    pub fn should_enrich(&self) -> bool {
+        self.enrich.unwrap_or(true)
+    }
+
+    pub fn should_store(&self) -> bool {
+        self.store.unwrap_or(true)
+    }
+
+    /// The partition and instance the request applies to. An instance that
+    /// is not given anywhere defaults to the partition.
+    pub fn resolve_scope(&self, partition: &str, instance: Option<&str>) -> (String, String) {
+        let partition = self
+            .partition
+            .clone()
+            .unwrap_or_else(|| partition.to_string());
+        let instance = self
+            .instance
+            .clone()
+            .or_else(|| instance.map(str::to_string))
+            .unwrap_or_else(|| partition.clone());
+        (partition, instance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Reservoir-Enrich", HeaderValue::from_static("off"));
+        headers.insert("X-Reservoir-Store", HeaderValue::from_static("false"));
+        headers.insert("X-Reservoir-Similar-Limit", HeaderValue::from_static("3"));
+        headers.insert("X-Reservoir-Partition", HeaderValue::from_static("scratch"));
+        headers.insert("X-Reservoir-Strategy", HeaderValue::from_static("graph-walk"));
+
+        let options = RequestOptions::from_headers(&headers).unwrap();
+
+        assert!(!options.should_enrich());
+        assert!(!options.should_store());
+        assert_eq!(options.similar_limit, Some(3));
+        assert_eq!(options.recent_limit, None);
+        assert_eq!(options.strategy, Some(Strategy::GraphWalk));
+        assert_eq!(
+            options.resolve_scope("default", None),
+            ("scratch".to_string(), "scratch".to_string())
+        );
+    }
+
+    #[test]
+    fn test_invalid_header_is_rejected() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Reservoir-Similar-Limit", HeaderValue::from_static("many"));
+        assert!(RequestOptions::from_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn test_body_options_are_stripped_and_win() {
+        let mut chat_request = ChatRequest::from_json(
+            r#"{
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": "hi"}],
+                "temperature": 0.5,
+                "reservoir": {"store": false, "instance": "notes", "strategy": "recent"}
+            }"#,
+        )
+        .unwrap();
+        let headers = RequestOptions {
+            store: Some(true),
+            enrich: Some(false),
+            ..Default::default()
+        };
+
+        let body = RequestOptions::take_from_request(&mut chat_request).unwrap();
+        let options = headers.merge(body);
+
+        assert!(!chat_request.extra.contains_key("reservoir"));
+        assert!(chat_request.extra.contains_key("temperature"));
+        assert!(!options.should_store());
+        assert!(!options.should_enrich());
+        assert_eq!(options.strategy, Some(Strategy::Recent));
+        assert_eq!(
+            options.resolve_scope("work", Some("chat")),
+            ("work".to_string(), "notes".to_string())
+        );
+    }
+}
@@ -1,37 +1,62 @@
 use anyhow::Error;
 use args::{Args, SubCommands};
 use clap::Parser;
+use commands::branches::{execute_branches, execute_walk};
 use commands::search::execute as search_execute;
 use commands::view::execute;
-use handler::completions::handle_with_partition;
+use errors::ReservoirError;
+use handler::auth::authenticate;
+use handler::completions::{
+    handle_with_partition, ChatCompletion, ChatCompletionBody, MODEL_SWITCHED_HEADER,
+};
+use handler::embeddings::handle_embeddings;
+use handler::messages::{handle_messages, MessagesBody};
+use handler::models::handle_models;
+use handler::options::RequestOptions;
+use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
 use http_body_util::Full;
 use hyper::body::Bytes;
 use hyper::body::Incoming;
-use hyper::{Method, Request, Response, StatusCode};
-use repos::message::AnyMessageRepository;
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Request, Response};
+use models::api_key::Permission;
+use models::strategy::Strategy;
 use repos::message::Neo4jMessageRepository;
+use state::AppState;
 use std::convert::Infallible;
+use std::sync::Arc;
 use tracing::{error, info};
 
 mod args;
 mod clients;
 mod commands;
+mod errors;
 mod handler;
 mod models;
 mod repos;
 mod services;
+mod state;
 mod utils;
 
+/// Paths may be prefixed with `/v1`, as in `/v1/partition/{p}/instance/{i}/...`
+fn strip_version(path: &str) -> &str {
+    path.strip_prefix("/v1").unwrap_or(path)
+}
+
 fn get_partition_from_path(path: &str) -> String {
-    path.strip_prefix("/partition/")
+    strip_version(path)
+        .strip_prefix("/partition/")
         .and_then(|rest| rest.split('/').next())
         .map(|s| s.to_string())
         .unwrap_or_else(|| "default".to_string())
 }
 
 fn get_instance_from_path(path: &str) -> Option<String> {
-    let parts: Vec<&str> = path.strip_prefix("/partition/")?.split('/').collect();
+    let parts: Vec<&str> = strip_version(path)
+        .strip_prefix("/partition/")?
+        .split('/')
+        .collect();
     if parts.len() >= 3 && parts[1] == "instance" {
         Some(parts[2].to_string())
     } else {
@@ -39,10 +64,37 @@ fn get_instance_from_path(path: &str) -> Option<String> {
     }
 }
 
+fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, Infallible> {
+    Full::new(chunk.into()).boxed()
+}
+
+/// Tells the client which model it asked for when a larger context model
+/// answered instead.
+fn add_model_switched_header(
+    response: &mut Response<BoxBody<Bytes, Infallible>>,
+    switched_from: Option<String>,
+) {
+    if let Some(value) = switched_from.and_then(|model| HeaderValue::from_str(&model).ok()) {
+        response.headers_mut().insert(MODEL_SWITCHED_HEADER, value);
+    }
+}
+
 fn is_chat_request(path: &str) -> bool {
     path.contains("/chat/completions")
 }
 
+fn is_embeddings_request(path: &str) -> bool {
+    path.ends_with("/embeddings")
+}
+
+fn is_messages_request(path: &str) -> bool {
+    path.ends_with("/messages")
+}
+
+fn is_models_request(path: &str) -> bool {
+    path.ends_with("/models")
+}
+
 fn is_search_request(path: &str) -> bool {
     path.contains("/command/search")
 }
@@ -51,39 +103,187 @@ fn is_view_request(path: &str) -> bool {
     path.contains("/command/view")
 }
 
-async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+fn is_branches_request(path: &str) -> bool {
+    path.contains("/command/branches/")
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    state: Arc<AppState>,
+) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
     info!("Received request: {} {}", req.method(), req.uri().path());
 
+    let access = match authenticate(&state, req.headers()).await {
+        Ok(access) => access,
+        Err(e) => {
+            error!("Rejected request: {}", e);
+            return Ok(e.into_response());
+        }
+    };
+
     match (req.method(), req.uri().path()) {
         (&Method::POST, path) if is_chat_request(path) => {
             info!("Chat request: {}", path);
             let partition = get_partition_from_path(path);
-            info!("Partition: {}", partition);
-            let instance = get_instance_from_path(path).unwrap_or(partition.clone());
-            info!("Instance: {}", instance);
+            let instance = get_instance_from_path(path);
+            let options = match RequestOptions::from_headers(req.headers()) {
+                Ok(options) => options,
+                Err(e) => return Ok(e.into_response()),
+            };
 
-            let whole_body = req.into_body().collect().await.unwrap().to_bytes();
-            let response_bytes =
-                handle_with_partition(partition.as_str(), instance.as_str(), whole_body).await;
-            let response_bytes = match response_bytes {
-                Ok(bytes) => bytes,
+            let whole_body = match req.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    return Ok(ReservoirError::BadRequest(format!(
+                        "Could not read request body: {}",
+                        e
+                    ))
+                    .into_response())
+                }
+            };
+            let response_bytes = handle_with_partition(
+                &state,
+                partition.as_str(),
+                instance.as_deref(),
+                options,
+                &access,
+                whole_body,
+            )
+            .await;
+            match response_bytes {
+                Ok(ChatCompletion { body, switched_from }) => {
+                    let mut response = match body {
+                        ChatCompletionBody::Complete(bytes) => Response::new(full(bytes)),
+                        ChatCompletionBody::Stream(body) => {
+                            let mut response = Response::new(body.boxed());
+                            let headers = response.headers_mut();
+                            headers.insert(
+                                header::CONTENT_TYPE,
+                                HeaderValue::from_static("text/event-stream"),
+                            );
+                            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+                            response
+                        }
+                    };
+                    add_model_switched_header(&mut response, switched_from);
+                    Ok(response)
+                }
                 Err(e) => {
                     error!("Error handling request: {}", e);
-                    return Ok(Response::new(Full::new(Bytes::from(
-                        "Internal Server Error",
-                    ))));
+                    Ok(ReservoirError::from(e).into_response())
+                }
+            }
+        }
+
+        (&Method::POST, path) if is_embeddings_request(path) => {
+            info!("Embeddings request: {}", path);
+            let partition = get_partition_from_path(path);
+            let instance = get_instance_from_path(path);
+            let options = match RequestOptions::from_headers(req.headers()) {
+                Ok(options) => options,
+                Err(e) => return Ok(e.into_response()),
+            };
+
+            let whole_body = match req.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    return Ok(ReservoirError::BadRequest(format!(
+                        "Could not read request body: {}",
+                        e
+                    ))
+                    .into_response())
+                }
+            };
+            match handle_embeddings(
+                &state,
+                partition.as_str(),
+                instance.as_deref(),
+                options,
+                &access,
+                whole_body,
+            )
+            .await
+            {
+                Ok(bytes) => Ok(Response::new(full(bytes))),
+                Err(e) => {
+                    error!("Error handling embeddings request: {}", e);
+                    Ok(ReservoirError::from(e).into_response())
+                }
+            }
+        }
+
+        (&Method::POST, path) if is_messages_request(path) => {
+            info!("Messages request: {}", path);
+            let partition = get_partition_from_path(path);
+            let instance = get_instance_from_path(path);
+            let options = match RequestOptions::from_headers(req.headers()) {
+                Ok(options) => options,
+                Err(e) => return Ok(e.into_anthropic_response()),
+            };
+
+            let whole_body = match req.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    return Ok(ReservoirError::BadRequest(format!(
+                        "Could not read request body: {}",
+                        e
+                    ))
+                    .into_anthropic_response())
                 }
             };
-            Ok(Response::new(Full::new(response_bytes)))
+            match handle_messages(
+                &state,
+                partition.as_str(),
+                instance.as_deref(),
+                options,
+                &access,
+                whole_body,
+            )
+            .await
+            {
+                Ok((body, switched_from)) => {
+                    let mut response = match body {
+                        MessagesBody::Json(bytes) => Response::new(full(bytes)),
+                        MessagesBody::EventStream(bytes) => {
+                            let mut response = Response::new(full(bytes));
+                            response.headers_mut().insert(
+                                header::CONTENT_TYPE,
+                                HeaderValue::from_static("text/event-stream"),
+                            );
+                            response
+                        }
+                    };
+                    add_model_switched_header(&mut response, switched_from);
+                    Ok(response)
+                }
+                Err(e) => {
+                    error!("Error handling messages request: {}", e);
+                    Ok(ReservoirError::from(e).into_anthropic_response())
+                }
+            }
+        }
+
+        (&Method::GET, path) if is_models_request(path) => {
+            let models = handle_models().await;
+            match serde_json::to_string(&models) {
+                Ok(json) => Ok(Response::new(full(json))),
+                Err(e) => Ok(ReservoirError::Internal(e.to_string()).into_response()),
+            }
         }
 
         (&Method::POST, "/echo") => {
-            let whole_body = req.into_body().collect().await.unwrap().to_bytes();
+            let whole_body = match req.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    return Ok(ReservoirError::BadRequest(format!(
+                        "Could not read request body: {}",
+                        e
+                    ))
+                    .into_response())
+                }
+            };
             let body = String::from_utf8_lossy(&whole_body);
-            Ok(Response::new(Full::new(Bytes::from(format!(
-                "You said: {}",
-                body
-            )))))
+            Ok(Response::new(full(format!("You said: {}", body))))
         }
 
         (&Method::GET, path) if is_search_request(path) => {
@@ -92,6 +292,9 @@ async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infalli
             info!("Partition: {}", partition);
             let instance = get_instance_from_path(path).unwrap_or(partition.clone());
             info!("Instance: {}", instance);
+            if let Err(e) = access.check(Permission::Search, &partition, &instance) {
+                return Ok(e.into_response());
+            }
 
             // Extract count from the path (last segment)
             let count = path
@@ -104,35 +307,46 @@ async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infalli
             let query = req.uri().query().unwrap_or("");
             let mut term = "".to_string();
             let mut semantic = false;
+            let mut strategy = None;
             for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
                 if key == "term" {
                     term = value.into_owned();
                 } else if key == "semantic" {
                     semantic = value == "true" || value == "1";
+                } else if key == "strategy" {
+                    match Strategy::parse(&value) {
+                        Some(value) => strategy = Some(value),
+                        None => {
+                            return Ok(ReservoirError::BadRequest(format!(
+                                "Unknown strategy '{}'",
+                                value
+                            ))
+                            .into_response())
+                        }
+                    }
                 }
             }
 
             if term.is_empty() {
-                let response =
-                    Response::new(Full::new(Bytes::from("Missing 'term' query parameter")));
-                return Ok(response);
+                return Ok(ReservoirError::BadRequest(
+                    "Missing 'term' query parameter".to_string(),
+                )
+                .into_response());
             }
 
-            let repo = AnyMessageRepository::new_neo4j();
+            let repo = state.any_message_repo();
             let result = search_execute(
-                &repo, partition, instance, count, term, semantic, false, false,
+                &repo, partition, instance, count, term, semantic, strategy, false,
             )
             .await;
             match result {
-                Ok(output) => {
-                    let json = serde_json::to_string(&output).unwrap();
-                    let response = Response::new(Full::new(Bytes::from(json)));
-                    Ok(response)
-                }
+                Ok(output) => match serde_json::to_string(&output) {
+                    Ok(json) => Ok(Response::new(full(json))),
+                    Err(e) => Ok(ReservoirError::Internal(e.to_string()).into_response()),
+                },
                 Err(e) => {
                     error!("Error executing search: {}", e);
-                    let response = Response::new(Full::new(Bytes::from(format!("Error: {}", e))));
-                    Ok(response)
+                    Ok(ReservoirError::from(e).into_response())
                 }
             }
         }
@@ -142,6 +356,9 @@ async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infalli
             info!("Partition: {}", partition);
             let instance = get_instance_from_path(path).unwrap_or(partition.clone());
             info!("Instance: {}", instance);
+            if let Err(e) = access.check(Permission::Read, &partition, &instance) {
+                return Ok(e.into_response());
+            }
 
             // the last part of the path should be the number, lets get it
             let count = path
@@ -152,29 +369,55 @@ async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infalli
             // convert to usize
             let count = count as usize;
 
-            let repo = AnyMessageRepository::new_neo4j();
+            let repo = state.any_message_repo();
 
             let result = execute(&repo, partition, instance, count).await;
 
             match result {
-                Ok(output) => {
-                    let json = serde_json::to_string(&output).unwrap();
-                    let response = Response::new(Full::new(Bytes::from(json)));
-                    Ok(response)
-                }
+                Ok(output) => match serde_json::to_string(&output) {
+                    Ok(json) => Ok(Response::new(full(json))),
+                    Err(e) => Ok(ReservoirError::Internal(e.to_string()).into_response()),
+                },
                 Err(e) => {
                     error!("Error executing command: {}", e);
-                    let response = Response::new(Full::new(Bytes::from(format!("Error: {}", e))));
-                    Ok(response)
+                    Ok(ReservoirError::from(e).into_response())
                 }
             }
         }
 
-        _ => {
-            let mut not_found = Response::new(Full::new(Bytes::from("Not Found")));
-            *not_found.status_mut() = StatusCode::NOT_FOUND;
-            Ok(not_found)
+        (&Method::GET, path) if is_branches_request(path) => {
+            let partition = get_partition_from_path(path);
+            let instance = get_instance_from_path(path).unwrap_or(partition.clone());
+            if let Err(e) = access.check(Permission::Read, &partition, &instance) {
+                return Ok(e.into_response());
+            }
+
+            // the last part of the path is the message id
+            let message_id = path.rsplit('/').next().unwrap_or_default().to_string();
+            let walk = url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+                .any(|(key, value)| key == "walk" && (value == "true" || value == "1"));
+
+            let repo = state.any_message_repo();
+            let result = if walk {
+                execute_walk(&repo, partition, instance, message_id)
+                    .await
+                    .and_then(|output| Ok(serde_json::to_string(&output)?))
+            } else {
+                execute_branches(&repo, partition, instance, message_id)
+                    .await
+                    .and_then(|output| Ok(serde_json::to_string(&output)?))
+            };
+
+            match result {
+                Ok(json) => Ok(Response::new(full(json))),
+                Err(e) => {
+                    error!("Error executing branches: {}", e);
+                    Ok(ReservoirError::from(e).into_response())
+                }
+            }
         }
+
+        _ => Ok(ReservoirError::NotFound("Not Found".to_string()).into_response()),
     }
 }
 
@@ -185,10 +428,11 @@ async fn main() -> Result<(), Error> {
         .with_env_filter(std::env::var("RUST_LOG").unwrap_or_else(|_| "reservoir=info".to_string()))
         .init();
     let args = Args::parse();
-    let repo = AnyMessageRepository::new_neo4j();
+    let state = AppState::connect().await?;
+    let repo = state.any_message_repo();
     match args.subcmd {
         Some(SubCommands::Start(_)) => {
-            commands::start::run(&repo).await?;
+            commands::start::run(state).await?;
         }
         Some(SubCommands::Config(_config_subcmd)) => {
             commands::config::run().await?;
@@ -205,9 +449,18 @@ async fn main() -> Result<(), Error> {
         Some(SubCommands::Search(ref search_cmd)) => {
             commands::search::run(&repo, search_cmd).await?;
         }
+        Some(SubCommands::Branches(ref branches_cmd)) => {
+            commands::branches::run(&repo, branches_cmd).await?;
+        }
         Some(SubCommands::Ingest(ref ingest_cmd)) => {
             commands::ingest::run(&repo, ingest_cmd).await?;
         }
+        Some(SubCommands::Keys(ref keys_cmd)) => {
+            commands::keys::run(&state.api_key_repo(), keys_cmd).await?;
+        }
+        Some(SubCommands::Synapses(ref synapses_cmd)) => {
+            commands::synapses::run(&repo, synapses_cmd).await?;
+        }
         None => {}
     };
     Ok(())
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::utils::sha256_hex;
+
+pub const API_KEY_PREFIX: &str = "rsv_";
+const WILDCARD: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// View stored messages
+    Read,
+    /// Send chat requests, which stores messages
+    Write,
+    /// Search stored messages
+    Search,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Search => "search",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "read" => Some(Permission::Read),
+            "write" => Some(Permission::Write),
+            "search" => Some(Permission::Search),
+            _ => None,
+        }
+    }
+}
+
+/// A key issued by Reservoir itself. Only the SHA-256 of the key is stored,
+/// the key is shown once when it is created.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub key_hash: String,
+    /// Partitions the key may access, `*` for all of them
+    pub partitions: Vec<String>,
+    /// Instances the key may access, `*` for all of them
+    pub instances: Vec<String>,
+    pub permissions: Vec<String>,
+    pub created_at: i64,
+}
+
+impl ApiKey {
+    /// Creates a new key, returning the stored record and the plain text key.
+    pub fn generate(
+        name: String,
+        partitions: Vec<String>,
+        instances: Vec<String>,
+        permissions: Vec<Permission>,
+    ) -> (Self, String) {
+        let secret = format!(
+            "{}{}{}",
+            API_KEY_PREFIX,
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+        let key = ApiKey {
+            id: Uuid::new_v4().to_string(),
+            name,
+            key_hash: hash_api_key(&secret),
+            partitions,
+            instances,
+            permissions: permissions.iter().map(|p| p.as_str().to_string()).collect(),
+            created_at: chrono::Utc::now().timestamp_millis(),
+        };
+        (key, secret)
+    }
+
+    pub fn allows(&self, permission: Permission, partition: &str, instance: &str) -> bool {
+        let matches = |scopes: &[String], value: &str| {
+            scopes.iter().any(|s| s == WILDCARD || s == value)
+        };
+        self.permissions.iter().any(|p| p == permission.as_str())
+            && matches(&self.partitions, partition)
+            && matches(&self.instances, instance)
+    }
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    sha256_hex(key.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_stores_only_the_hash() {
+        let (key, secret) = ApiKey::generate(
+            "test".to_string(),
+            vec!["*".to_string()],
+            vec!["*".to_string()],
+            vec![Permission::Read],
+        );
+
+        assert!(secret.starts_with(API_KEY_PREFIX));
+        assert_ne!(key.key_hash, secret);
+        assert_eq!(key.key_hash, hash_api_key(&secret));
+    }
+
+    #[test]
+    fn test_allows_checks_scope_and_permission() {
+        let (key, _) = ApiKey::generate(
+            "work".to_string(),
+            vec!["work".to_string()],
+            vec!["*".to_string()],
+            vec![Permission::Read, Permission::Search],
+        );
+
+        assert!(key.allows(Permission::Read, "work", "notes"));
+        assert!(key.allows(Permission::Search, "work", "chat"));
+        assert!(!key.allows(Permission::Write, "work", "notes"));
+        assert!(!key.allows(Permission::Read, "personal", "notes"));
+    }
+}
@@ -1,10 +1,17 @@
 use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
 
-use crate::clients::openai::types::Message;
+use crate::clients::openai::types::{ContentPart, Message, ToolCall};
+use crate::repos::blob::BlobStore;
 
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MessageNode {
+    /// Stable id of this message. Nodes stored before messages had ids get
+    /// one from `migrations/message_ids_and_order.cypher`.
+    #[serde(default)]
+    pub id: String,
     pub trace_id: String,
     pub partition: String,
     pub instance: String,
@@ -12,7 +19,24 @@ pub struct MessageNode {
     pub role: String,
     pub embedding: Vec<f32>,
     pub url: Option<String>,
+    /// JSON encoded content parts of a message sent with media, the media
+    /// replaced by references into the blob store
+    pub parts: Option<String>,
     pub timestamp: i64,
+    /// JSON encoded tool calls made by an assistant message
+    pub tool_calls: Option<String>,
+    /// The tool call a `tool` message is the result of
+    pub tool_call_id: Option<String>,
+    /// The upstream model that produced an assistant message
+    pub model: Option<String>,
+    /// The model the client asked for, when a larger context model answered
+    /// instead
+    pub switched_from: Option<String>,
+    /// Identifies the message and its position in the conversation, see
+    /// `Message::chain_hash`
+    pub chain_hash: Option<String>,
+    /// Chain hash of the message before this one in the conversation
+    pub parent_hash: Option<String>,
 }
 
 #[allow(dead_code)]
@@ -26,19 +50,28 @@ impl MessageNode {
         url: Option<String>,
     ) -> Self {
         MessageNode {
+            id: Uuid::new_v4().to_string(),
             trace_id,
             partition,
             instance,
             role,
             content,
             url,
+            parts: None,
             embedding: vec![],
             timestamp: chrono::Utc::now().timestamp_millis(),
+            tool_calls: None,
+            tool_call_id: None,
+            model: None,
+            switched_from: None,
+            chain_hash: None,
+            parent_hash: None,
         }
     }
 
     pub fn default() -> Self {
         MessageNode {
+            id: Uuid::new_v4().to_string(),
             trace_id: "test-traceid".to_string(),
             partition: "default".to_string(),
             instance: "default".to_string(),
@@ -46,7 +79,42 @@ impl MessageNode {
             embedding: vec![],
             content: None,
             url: None,
+            parts: None,
             timestamp: chrono::Utc::now().timestamp_millis(),
+            tool_calls: None,
+            tool_call_id: None,
+            model: None,
+            switched_from: None,
+            chain_hash: None,
+            parent_hash: None,
+        }
+    }
+
+    pub fn get_tool_calls(&self) -> Option<Vec<ToolCall>> {
+        self.tool_calls
+            .as_deref()
+            .and_then(|calls| serde_json::from_str(calls).ok())
+    }
+
+    /// The content parts of the message, if it was sent with any. Until
+    /// `load_media` ran the media in them are references into the blob store.
+    pub fn get_parts(&self) -> Option<Vec<ContentPart>> {
+        self.parts
+            .as_deref()
+            .and_then(|parts| serde_json::from_str(parts).ok())
+    }
+
+    /// Reads the media of the message back from the blob store, so the
+    /// messages made from the node carry it. Only done for messages that are
+    /// injected or shown, converting a node does no IO.
+    pub async fn load_media(&mut self) {
+        let Some(mut parts) = self.get_parts() else {
+            return;
+        };
+        BlobStore::default().load_parts(&mut parts).await;
+        match serde_json::to_string(&parts) {
+            Ok(parts) => self.parts = Some(parts),
+            Err(e) => warn!("Could not load content parts of message {}: {}", self.id, e),
         }
     }
 
@@ -54,9 +122,48 @@ impl MessageNode {
         Message {
             role: self.role.clone(),
             content: self.content.clone().unwrap_or_default(),
+            parts: self.get_parts(),
+            tool_calls: self.get_tool_calls(),
+            tool_call_id: self.tool_call_id.clone(),
+            ..Default::default()
         }
     }
 
+    /// Converts the node into a message that can be injected as context.
+    /// Tool calls and their results are written out as plain assistant text,
+    /// since providers reject tool messages that are not directly preceded by
+    /// the call they answer.
+    pub fn to_context_message(&self) -> Message {
+        let content = self.content.clone().unwrap_or_default();
+        if self.role == "tool" {
+            return Message {
+                role: "assistant".to_string(),
+                content: format!(
+                    "Tool result for call {}: {}",
+                    self.tool_call_id.clone().unwrap_or_default(),
+                    content
+                ),
+                ..Default::default()
+            };
+        }
+
+        let mut message = Message {
+            role: self.role.clone(),
+            content,
+            parts: self.get_parts(),
+            ..Default::default()
+        };
+        if let Some(calls) = self.get_tool_calls() {
+            let descriptions: Vec<String> = calls.iter().map(ToolCall::describe).collect();
+            let mut text = descriptions.join("\n");
+            if !message.content.is_empty() {
+                text.insert(0, '\n');
+            }
+            message.push_text(&text);
+        }
+        message
+    }
+
     pub fn from_message(
         message: &Message,
         trace_id: &str,
@@ -65,6 +172,7 @@ impl MessageNode {
         embedding: Vec<f32>,
     ) -> Self {
         MessageNode {
+            id: Uuid::new_v4().to_string(),
             trace_id: trace_id.to_string(),
             partition: partition.to_string(),
             instance: instance.to_string(),
@@ -72,7 +180,25 @@ impl MessageNode {
             embedding,
             content: Some(message.content.clone()),
             url: None,
+            parts: None,
             timestamp: chrono::Utc::now().timestamp_millis(),
+            tool_calls: message
+                .tool_calls
+                .as_ref()
+                .and_then(|calls| serde_json::to_string(calls).ok()),
+            tool_call_id: message.tool_call_id.clone(),
+            model: None,
+            switched_from: None,
+            chain_hash: None,
+            parent_hash: None,
         }
     }
 }
+
+/// Reads the media of the nodes back from the blob store, see
+/// `MessageNode::load_media`.
+pub async fn load_media(nodes: &mut [MessageNode]) {
+    for node in nodes.iter_mut() {
+        node.load_media().await;
+    }
+}
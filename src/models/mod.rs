@@ -1,4 +1,5 @@
 pub mod message_node;
 pub mod embedding_node;
 pub mod chat_response;
-
+pub mod api_key;
+pub mod strategy;
@@ -0,0 +1,55 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The built in strategies, as they are named in `reservoir.toml`, the
+/// `X-Reservoir-Strategy` header and the `--strategy` flag.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    None,
+    Recent,
+    Semantic,
+    GraphWalk,
+    #[default]
+    Hybrid,
+}
+
+impl Strategy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().replace('-', "_").as_str() {
+            "none" => Some(Strategy::None),
+            "recent" => Some(Strategy::Recent),
+            "semantic" => Some(Strategy::Semantic),
+            "graph_walk" => Some(Strategy::GraphWalk),
+            "hybrid" => Some(Strategy::Hybrid),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Strategy::None => "none",
+            Strategy::Recent => "recent",
+            Strategy::Semantic => "semantic",
+            Strategy::GraphWalk => "graph_walk",
+            Strategy::Hybrid => "hybrid",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(Strategy::parse("graph-walk"), Some(Strategy::GraphWalk));
+        assert_eq!(Strategy::parse(" Hybrid "), Some(Strategy::Hybrid));
+        assert_eq!(Strategy::parse("everything"), None);
+        assert_eq!(Strategy::parse(&Strategy::GraphWalk.to_string()), Some(Strategy::GraphWalk));
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::Error;
+use neo4rs::{query, Graph};
+
+use crate::models::api_key::ApiKey;
+
+pub trait ApiKeyRepository {
+    async fn save_api_key(&self, api_key: &ApiKey) -> Result<(), Error>;
+    async fn find_api_key_by_hash(&self, key_hash: &str) -> Result<Option<ApiKey>, Error>;
+    async fn get_api_keys(&self) -> Result<Vec<ApiKey>, Error>;
+    async fn has_api_keys(&self) -> Result<bool, Error>;
+    async fn delete_api_key(&self, id: &str) -> Result<bool, Error>;
+}
+
+pub struct Neo4jApiKeyRepository {
+    graph: Graph,
+}
+
+impl Neo4jApiKeyRepository {
+    pub fn new(graph: Graph) -> Self {
+        Neo4jApiKeyRepository { graph }
+    }
+}
+
+impl ApiKeyRepository for Neo4jApiKeyRepository {
+    async fn save_api_key(&self, api_key: &ApiKey) -> Result<(), Error> {
+        let graph = &self.graph;
+        let q = query(
+            r#"
+            CREATE (k:ApiKey {
+                id: $id,
+                name: $name,
+                key_hash: $key_hash,
+                partitions: $partitions,
+                instances: $instances,
+                permissions: $permissions,
+                created_at: $created_at
+            })
+            "#,
+        )
+        .param("id", api_key.id.clone())
+        .param("name", api_key.name.clone())
+        .param("key_hash", api_key.key_hash.clone())
+        .param("partitions", api_key.partitions.clone())
+        .param("instances", api_key.instances.clone())
+        .param("permissions", api_key.permissions.clone())
+        .param("created_at", api_key.created_at);
+        graph.run(q).await?;
+        Ok(())
+    }
+
+    async fn find_api_key_by_hash(&self, key_hash: &str) -> Result<Option<ApiKey>, Error> {
+        let graph = &self.graph;
+        let q = query("MATCH (k:ApiKey {key_hash: $key_hash}) RETURN k")
+            .param("key_hash", key_hash);
+        let mut result = graph.execute(q).await?;
+        match result.next().await? {
+            Some(row) => Ok(Some(row.get("k")?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_api_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        let graph = &self.graph;
+        let mut result = graph
+            .execute(query("MATCH (k:ApiKey) RETURN k ORDER BY k.created_at ASC"))
+            .await?;
+        let mut keys = Vec::new();
+        while let Some(row) = result.next().await? {
+            let key: ApiKey = row.get("k")?;
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    async fn has_api_keys(&self) -> Result<bool, Error> {
+        let mut result = self
+            .graph
+            .execute(query("MATCH (k:ApiKey) RETURN count(k) AS keys"))
+            .await?;
+        let keys: i64 = match result.next().await? {
+            Some(row) => row.get("keys")?,
+            None => 0,
+        };
+        Ok(keys > 0)
+    }
+
+    async fn delete_api_key(&self, id: &str) -> Result<bool, Error> {
+        let graph = &self.graph;
+        let q = query("MATCH (k:ApiKey {id: $id}) DELETE k RETURN count(k) AS deleted")
+            .param("id", id);
+        let mut result = graph.execute(q).await?;
+        let deleted: i64 = match result.next().await? {
+            Some(row) => row.get("deleted")?,
+            None => 0,
+        };
+        Ok(deleted > 0)
+    }
+}
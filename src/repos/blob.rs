@@ -0,0 +1,236 @@
+use std::path::PathBuf;
+
+use anyhow::Error;
+use openssl::base64;
+use serde_json::Value;
+use tokio::fs;
+use tracing::warn;
+
+use crate::clients::openai::types::ContentPart;
+use crate::repos::config::get_blob_store_path;
+use crate::utils::sha256_hex;
+
+const BLOB_URL_PREFIX: &str = "blob:sha256:";
+
+/// A local content addressed store for message content that is not text,
+/// such as images and audio. Blobs are named by the SHA-256 of their bytes,
+/// so the same content is only ever written once.
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(root: PathBuf) -> Self {
+        BlobStore { root }
+    }
+
+    pub fn default() -> Self {
+        BlobStore::new(get_blob_store_path())
+    }
+
+    /// Writes the bytes to the store and returns their hash.
+    pub async fn put(&self, data: &[u8]) -> Result<String, Error> {
+        let hash = sha256_hex(data);
+        let path = self.root.join(&hash);
+        if !fs::try_exists(&path).await? {
+            fs::create_dir_all(&self.root).await?;
+            fs::write(&path, data).await?;
+        }
+        Ok(hash)
+    }
+
+    pub async fn get(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::msg(format!("Invalid blob hash: {}", hash)));
+        }
+        Ok(fs::read(self.root.join(hash)).await?)
+    }
+
+    /// Moves the media of the content parts into the store and returns the
+    /// parts to keep on the `MessageNode`. Every base64 payload is stored as
+    /// its own blob and replaced by a reference to it, so the same image sent
+    /// in many messages is only written once. Text is kept as it is.
+    pub async fn put_parts(&self, parts: &[ContentPart]) -> Result<Vec<ContentPart>, Error> {
+        let mut parts = parts.to_vec();
+        for part in parts.iter_mut() {
+            let kind = part.kind.clone();
+            for field in payload_fields(part) {
+                let (prefix, payload) = split_payload(field);
+                if payload.starts_with(BLOB_URL_PREFIX) {
+                    continue;
+                }
+                let Ok(data) = base64::decode_block(payload) else {
+                    warn!("Keeping a {} part inline, its data is not base64", kind);
+                    continue;
+                };
+                let hash = self.put(&data).await?;
+                *field = format!("{}{}{}", prefix, BLOB_URL_PREFIX, hash);
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Replaces the blob references in parts created with `put_parts` with
+    /// the data they point to. A part whose media cannot be read becomes a
+    /// text placeholder, since a reference sent upstream in its place would
+    /// make the provider reject the whole request.
+    pub async fn load_parts(&self, parts: &mut [ContentPart]) {
+        for part in parts.iter_mut() {
+            if let Err(e) = self.load_part(part).await {
+                warn!("Could not load the media of a {} part: {}", part.kind, e);
+                *part = ContentPart::text(&format!("[{} not available]", part.kind));
+            }
+        }
+    }
+
+    async fn load_part(&self, part: &mut ContentPart) -> Result<(), Error> {
+        for field in payload_fields(part) {
+            let (prefix, payload) = split_payload(field);
+            if let Some(hash) = payload.strip_prefix(BLOB_URL_PREFIX) {
+                let data = self.get(hash).await?;
+                *field = format!("{}{}", prefix, base64::encode_block(&data));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The fields of a content part that carry base64 data: `data:` URLs
+/// anywhere in the part, and the raw audio of `input_audio` parts.
+fn payload_fields(part: &mut ContentPart) -> Vec<&mut String> {
+    let mut fields = Vec::new();
+    for (key, value) in part.extra.iter_mut() {
+        if key != "input_audio" {
+            collect_data_urls(value, &mut fields);
+            continue;
+        }
+        for (key, value) in value.as_object_mut().into_iter().flatten() {
+            match (key.as_str(), value) {
+                ("data", Value::String(data)) => fields.push(data),
+                (_, value) => collect_data_urls(value, &mut fields),
+            }
+        }
+    }
+    fields
+}
+
+fn collect_data_urls<'a>(value: &'a mut Value, fields: &mut Vec<&'a mut String>) {
+    match value {
+        Value::String(url) if split_payload(url).0.ends_with(";base64,") => fields.push(url),
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| collect_data_urls(item, fields)),
+        Value::Object(map) => map
+            .values_mut()
+            .for_each(|item| collect_data_urls(item, fields)),
+        _ => {}
+    }
+}
+
+/// Splits a field into what comes before its base64 data and the data,
+/// `data:image/png;base64,` and the image for a `data:` URL.
+fn split_payload(field: &str) -> (&str, &str) {
+    match field.find(',') {
+        Some(comma) if field.starts_with("data:") => field.split_at(comma + 1),
+        _ => ("", field),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store() -> BlobStore {
+        let mut root = std::env::temp_dir();
+        root.push(format!("reservoir-blobs-{}", uuid::Uuid::new_v4()));
+        BlobStore::new(root)
+    }
+
+    #[tokio::test]
+    async fn test_put_is_content_addressed() {
+        let store = temp_store();
+        let first = store.put(b"image bytes").await.unwrap();
+        let second = store.put(b"image bytes").await.unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(store.get(&first).await.unwrap(), b"image bytes");
+        let _ = std::fs::remove_dir_all(&store.root);
+    }
+
+    #[tokio::test]
+    async fn test_parts_round_trip() {
+        let store = temp_store();
+        let parts: Vec<ContentPart> = serde_json::from_str(
+            r#"[
+                {"type": "text", "text": "What is in this image?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,aW1hZ2UgYnl0ZXM="}},
+                {"type": "input_audio", "input_audio": {"data": "YXVkaW8gYnl0ZXM=", "format": "wav"}}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut stored = store.put_parts(&parts).await.unwrap();
+        let image_hash = sha256_hex(b"image bytes");
+        assert_eq!(stored[0].text.as_deref(), Some("What is in this image?"));
+        assert_eq!(
+            stored[1].extra["image_url"]["url"],
+            format!("data:image/png;base64,{}{}", BLOB_URL_PREFIX, image_hash)
+        );
+        assert_eq!(
+            stored[2].extra["input_audio"]["data"],
+            format!("{}{}", BLOB_URL_PREFIX, sha256_hex(b"audio bytes"))
+        );
+        assert_eq!(store.get(&image_hash).await.unwrap(), b"image bytes");
+
+        store.load_parts(&mut stored).await;
+        assert_eq!(
+            serde_json::to_value(&stored).unwrap(),
+            serde_json::to_value(&parts).unwrap()
+        );
+        let _ = std::fs::remove_dir_all(&store.root);
+    }
+
+    #[tokio::test]
+    async fn test_same_media_is_stored_once() {
+        let store = temp_store();
+        let part: ContentPart = serde_json::from_str(
+            r#"{"type": "image_url", "image_url": {"url": "data:image/png;base64,aW1hZ2UgYnl0ZXM="}}"#,
+        )
+        .unwrap();
+        let question = ContentPart::text("What is in this image?");
+        let follow_up = ContentPart::text("And now?");
+
+        let first = store.put_parts(&[question, part.clone()]).await.unwrap();
+        let second = store.put_parts(&[follow_up, part]).await.unwrap();
+
+        assert_eq!(first[1].extra, second[1].extra);
+        assert_eq!(std::fs::read_dir(&store.root).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(&store.root);
+    }
+
+    #[tokio::test]
+    async fn test_missing_media_becomes_placeholder() {
+        let store = temp_store();
+        let mut parts: Vec<ContentPart> = serde_json::from_str(&format!(
+            r#"[
+                {{"type": "text", "text": "What is in this image?"}},
+                {{"type": "image_url", "image_url": {{"url": "data:image/png;base64,{}{}"}}}}
+            ]"#,
+            BLOB_URL_PREFIX,
+            sha256_hex(b"deleted image")
+        ))
+        .unwrap();
+
+        store.load_parts(&mut parts).await;
+
+        assert_eq!(parts[0].text.as_deref(), Some("What is in this image?"));
+        assert!(parts[1].is_text());
+        assert_eq!(parts[1].text.as_deref(), Some("[image_url not available]"));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_paths() {
+        let store = temp_store();
+        assert!(store.get("../config").await.is_err());
+    }
+}
@@ -1,10 +1,16 @@
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use dirs_next::config_dir;
 
+use crate::models::strategy::Strategy;
+use crate::utils::Tokenizer;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ReservoirConfig {
     #[serde(default = "default_neo4j_uri")]
@@ -15,8 +21,327 @@ pub struct ReservoirConfig {
     pub neo4j_password: Option<String>,
     #[serde(default = "default_reservoir_port")]
     pub reservoir_port: Option<u16>,
-    #[serde(default = "default_neo4j_database")]
+    /// Database to store messages in, the server's default database when
+    /// unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub neo4j_database: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azure: Option<AzureConfig>,
+    /// `[[providers]]` entries, added to or replacing the built in ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub providers: Vec<ProviderConfig>,
+    /// `[[models]]` entries, added to or replacing the built in ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<ModelConfig>,
+    /// `[[routes]]`, logical model names resolved by rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<RouteConfig>,
+    /// `[synapses]`, how messages are linked to each other
+    #[serde(default)]
+    pub synapses: SynapseConfig,
+    /// `[enrichment]`, how much context is injected into a request
+    #[serde(default)]
+    pub enrichment: EnrichmentConfig,
+    /// `[partitions.<name>]`, settings that differ for one partition
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub partitions: HashMap<String, PartitionConfig>,
+}
+
+/// How messages are linked by synapses. Unset values fall back to the
+/// global section and then to the defaults below.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SynapseConfig {
+    /// Minimum cosine similarity of consecutive messages, default 0.85
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+    /// How many synapses enrichment follows from a message, default 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_hops: Option<usize>,
+    /// How many similar messages each new message is linked to, default 3,
+    /// 0 turns the semantic synapses off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub semantic_neighbours: Option<usize>,
+    /// Minimum cosine similarity of a semantic synapse, default 0.9
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub semantic_threshold: Option<f64>,
+}
+
+impl SynapseConfig {
+    fn or(&self, fallback: &SynapseConfig) -> SynapseConfig {
+        SynapseConfig {
+            threshold: self.threshold.or(fallback.threshold),
+            max_hops: self.max_hops.or(fallback.max_hops),
+            semantic_neighbours: self.semantic_neighbours.or(fallback.semantic_neighbours),
+            semantic_threshold: self.semantic_threshold.or(fallback.semantic_threshold),
+        }
+    }
+
+    fn settings(&self) -> SynapseSettings {
+        SynapseSettings {
+            threshold: self.threshold.unwrap_or(0.85),
+            max_hops: self.max_hops.unwrap_or(10).max(1),
+            semantic_neighbours: self.semantic_neighbours.unwrap_or(3),
+            semantic_threshold: self.semantic_threshold.unwrap_or(0.9),
+        }
+    }
+}
+
+/// How much history is injected into a request. Unset values fall back to
+/// the global section and then to the defaults below.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct EnrichmentConfig {
+    /// How the injected messages are found, default `hybrid`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<Strategy>,
+    /// How many semantically similar messages to look up, default 7
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similar_limit: Option<usize>,
+    /// How many recent messages to look up, default 15
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recent_limit: Option<usize>,
+    /// How many times more candidates the vector index is asked for, as it
+    /// searches every partition, default 3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oversample: Option<usize>,
+    /// Tokens the similar messages may take up, default 4000
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub semantic_token_budget: Option<usize>,
+    /// Tokens the recent messages may take up, default 4000
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recent_token_budget: Option<usize>,
+}
+
+impl EnrichmentConfig {
+    fn or(&self, fallback: &EnrichmentConfig) -> EnrichmentConfig {
+        EnrichmentConfig {
+            strategy: self.strategy.or(fallback.strategy),
+            similar_limit: self.similar_limit.or(fallback.similar_limit),
+            recent_limit: self.recent_limit.or(fallback.recent_limit),
+            oversample: self.oversample.or(fallback.oversample),
+            semantic_token_budget: self.semantic_token_budget.or(fallback.semantic_token_budget),
+            recent_token_budget: self.recent_token_budget.or(fallback.recent_token_budget),
+        }
+    }
+
+    fn settings(&self) -> EnrichmentSettings {
+        EnrichmentSettings {
+            strategy: self.strategy.unwrap_or_default(),
+            similar_limit: self.similar_limit.unwrap_or(7),
+            recent_limit: self.recent_limit.unwrap_or(15),
+            oversample: self.oversample.unwrap_or(3).max(1),
+            semantic_token_budget: self.semantic_token_budget.unwrap_or(4000),
+            recent_token_budget: self.recent_token_budget.unwrap_or(4000),
+        }
+    }
+}
+
+/// Overrides of the `[synapses]` and `[enrichment]` sections for one
+/// partition, for example `[partitions.work.enrichment]`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PartitionConfig {
+    #[serde(default)]
+    pub synapses: SynapseConfig,
+    #[serde(default)]
+    pub enrichment: EnrichmentConfig,
+}
+
+/// The synapse settings that apply to a partition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SynapseSettings {
+    pub threshold: f64,
+    pub max_hops: usize,
+    pub semantic_neighbours: usize,
+    pub semantic_threshold: f64,
+}
+
+/// The enrichment settings that apply to a partition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnrichmentSettings {
+    pub strategy: Strategy,
+    pub similar_limit: usize,
+    pub recent_limit: usize,
+    pub oversample: usize,
+    pub semantic_token_budget: usize,
+    pub recent_token_budget: usize,
+}
+
+impl ReservoirConfig {
+    pub fn synapse_settings(&self, partition: &str) -> SynapseSettings {
+        match self.partitions.get(partition) {
+            Some(overrides) => overrides.synapses.or(&self.synapses).settings(),
+            None => self.synapses.settings(),
+        }
+    }
+
+    pub fn enrichment_settings(&self, partition: &str) -> EnrichmentSettings {
+        match self.partitions.get(partition) {
+            Some(overrides) => overrides.enrichment.or(&self.enrichment).settings(),
+            None => self.enrichment.settings(),
+        }
+    }
+}
+
+/// A logical model such as `fast` or `local` that clients can ask for.
+/// The first matching rule picks the model, `default` is used otherwise.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RouteConfig {
+    pub name: String,
+    pub default: String,
+    #[serde(default)]
+    pub rules: Vec<RouteRule>,
+}
+
+/// A rule matches when every condition it sets holds.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RouteRule {
+    pub model: String,
+    pub partition: Option<String>,
+    pub instance: Option<String>,
+    /// Matches prompts of at least this many tokens
+    pub min_tokens: Option<usize>,
+    /// Matches prompts of at most this many tokens
+    pub max_tokens: Option<usize>,
+    pub has_images: Option<bool>,
+    /// Local hours as `start-end`, e.g. `9-17`, or `22-6` across midnight
+    pub hours: Option<String>,
+}
+
+/// How Reservoir talks to a provider.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+    /// An OpenAI compatible `/chat/completions` endpoint
+    Openai,
+    /// Ollama's OpenAI compatible endpoint, whose models are listed at `/api/tags`
+    Ollama,
+    /// An Azure OpenAI resource, `base_url` is its endpoint
+    Azure,
+    Anthropic,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProviderConfig {
+    pub name: String,
+    pub kind: ProviderKind,
+    /// The chat completions (or messages) URL, or the endpoint for Azure
+    pub base_url: String,
+    pub api_key: Option<String>,
+    /// Environment variable holding the API key, used when `api_key` is not set
+    pub api_key_env: Option<String>,
+    /// Extra headers sent with every request
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Azure only
+    pub api_version: Option<String>,
+    /// Seconds to wait for the provider to start answering, and at most
+    /// between two chunks of its answer
+    pub timeout_secs: Option<u64>,
+    /// Seconds to wait for a connection to the provider
+    pub connect_timeout_secs: Option<u64>,
+    /// How often a rate limited or failing request is sent again
+    pub max_retries: Option<u32>,
+}
+
+impl ProviderConfig {
+    pub fn api_key(&self) -> String {
+        self.api_key
+            .clone()
+            .or_else(|| self.api_key_env.as_ref().and_then(|var| env::var(var).ok()))
+            .unwrap_or_default()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS))
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+    }
+}
+
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MAX_RETRIES: u32 = 2;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ModelConfig {
+    /// Model name, a trailing `*` matches any name with that prefix
+    pub name: String,
+    /// Name of the provider serving the model
+    pub provider: String,
+    /// Name sent to the provider (the deployment for Azure), defaults to the
+    /// requested name
+    pub upstream: Option<String>,
+    #[serde(default = "default_context_window")]
+    pub context_window: usize,
+    #[serde(default = "default_output_tokens")]
+    pub output_tokens: usize,
+    #[serde(default)]
+    pub tokenizer: Tokenizer,
+    /// e.g. `tools`, `vision`, `streaming`. An empty list allows everything.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Other names the model can be requested by
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Models tried in order when this one keeps failing
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    /// Model with a larger context window to send prompts that do not fit
+    /// this one to, instead of truncating or rejecting them
+    pub larger_context: Option<String>,
+}
+
+fn default_context_window() -> usize {
+    128_000
+}
+
+fn default_output_tokens() -> usize {
+    4_096
+}
+
+/// The `[azure]` section, for sending requests to Azure OpenAI deployments.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct AzureConfig {
+    /// e.g. `https://my-resource.openai.azure.com`
+    pub endpoint: String,
+    /// Falls back to the `AZURE_OPENAI_API_KEY` environment variable
+    pub api_key: Option<String>,
+    #[serde(default = "default_azure_api_version")]
+    pub api_version: String,
+    /// Reservoir model name to Azure deployment name
+    #[serde(default)]
+    pub deployments: HashMap<String, String>,
+    /// Deployment used for embeddings, OpenAI is used when not set
+    pub embedding_deployment: Option<String>,
+}
+
+fn default_azure_api_version() -> String {
+    "2024-10-21".to_string()
+}
+
+impl AzureConfig {
+    pub fn api_key(&self) -> String {
+        self.api_key
+            .clone()
+            .or_else(|| env::var("AZURE_OPENAI_API_KEY").ok())
+            .unwrap_or_default()
+    }
+
+    /// URL of an operation such as `chat/completions` on a deployment.
+    pub fn deployment_url(&self, deployment: &str, operation: &str) -> String {
+        format!(
+            "{}/openai/deployments/{}/{}?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            deployment,
+            operation,
+            self.api_version
+        )
+    }
 }
 
 fn default_neo4j_uri() -> Option<String> {
@@ -31,9 +356,16 @@ fn default_neo4j_password() -> Option<String> {
 fn default_reservoir_port() -> Option<u16> {
     Some(3017)
 }
-fn default_neo4j_database() -> Option<String> {
-    Some("reservoir".to_string())
-}
+/// The `reservoir.toml` earlier versions wrote when there was none. Its
+/// `neo4j_database` was never used, so a file left as written is replaced
+/// with the current default, which keeps using the same database.
+const LEGACY_DEFAULT_CONFIG: &str = r#"
+neo4j_uri = "bolt://localhost:7687"
+neo4j_user = "neo4j"
+neo4j_password = "password"
+reservoir_port = 3017
+neo4j_database = "reservoir"
+"#;
 
 impl Default for ReservoirConfig {
     fn default() -> Self {
@@ -42,7 +374,14 @@ impl Default for ReservoirConfig {
             neo4j_user: default_neo4j_user(),
             neo4j_password: default_neo4j_password(),
             reservoir_port: default_reservoir_port(),
-            neo4j_database: default_neo4j_database(),
+            neo4j_database: None,
+            azure: None,
+            providers: Vec::new(),
+            models: Vec::new(),
+            routes: Vec::new(),
+            synapses: SynapseConfig::default(),
+            enrichment: EnrichmentConfig::default(),
+            partitions: HashMap::new(),
         }
     }
 }
@@ -56,10 +395,32 @@ fn get_reservoir_config_path() -> PathBuf {
     path
 }
 
+/// Directory of the local content addressed blob store, next to the config file.
+pub fn get_blob_store_path() -> PathBuf {
+    let mut path = get_reservoir_config_path();
+    path.set_file_name("blobs");
+    path
+}
+
+fn is_legacy_default(content: &str) -> bool {
+    let legacy: toml::Table = toml::from_str(LEGACY_DEFAULT_CONFIG).unwrap_or_default();
+    toml::from_str::<toml::Table>(content).is_ok_and(|table| table == legacy)
+}
+
 fn load_config_file() -> ReservoirConfig {
     let path = get_reservoir_config_path();
     if path.exists() {
         let content = fs::read_to_string(&path).unwrap_or_default();
+        if is_legacy_default(&content) {
+            warn!(
+                "Updating {} written by an earlier version, its neo4j_database was never used",
+                path.display()
+            );
+            let default = ReservoirConfig::default();
+            let toml_str = toml::to_string_pretty(&default).unwrap_or_default();
+            let _ = fs::write(&path, toml_str);
+            return default;
+        }
         toml::from_str(&content).unwrap_or_default()
     } else {
         // Create the directory and file, and write defaults
@@ -95,9 +456,167 @@ pub fn get_neo4j_password() -> String {
         .unwrap_or_else(|| "password".to_string())
 }
 
+/// The database named by `NEO4J_DATABASE` or else `neo4j_database`, `None`
+/// to use the server's default database.
+pub fn get_neo4j_database() -> Option<String> {
+    resolve_neo4j_database(
+        env::var("NEO4J_DATABASE").ok(),
+        get_config().neo4j_database.as_deref(),
+    )
+}
+
+fn resolve_neo4j_database(env: Option<String>, file: Option<&str>) -> Option<String> {
+    env.filter(|d| !d.is_empty())
+        .or_else(|| file.filter(|d| !d.is_empty()).map(str::to_string))
+}
+
 pub fn get_reservoir_port() -> u16 {
     get_config().reservoir_port
         .or_else(|| env::var("RESERVOIR_PORT").ok().and_then(|v| v.parse().ok()))
         .unwrap_or(3017)
 }
 
+pub fn get_azure_config() -> Option<&'static AzureConfig> {
+    get_config().azure.as_ref()
+}
+
+pub fn get_provider_configs() -> &'static [ProviderConfig] {
+    &get_config().providers
+}
+
+pub fn get_model_configs() -> &'static [ModelConfig] {
+    &get_config().models
+}
+
+pub fn get_route_configs() -> &'static [RouteConfig] {
+    &get_config().routes
+}
+
+pub fn get_synapse_settings(partition: &str) -> SynapseSettings {
+    get_config().synapse_settings(partition)
+}
+
+pub fn get_enrichment_settings(partition: &str) -> EnrichmentSettings {
+    get_config().enrichment_settings(partition)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_azure_section() {
+        let config: ReservoirConfig = toml::from_str(
+            r#"
+            [azure]
+            endpoint = "https://my-resource.openai.azure.com/"
+            api_key = "azure-key"
+            embedding_deployment = "ada"
+
+            [azure.deployments]
+            "gpt-4o" = "gpt4o-prod"
+            "#,
+        )
+        .unwrap();
+        let azure = config.azure.unwrap();
+
+        assert_eq!(azure.deployments["gpt-4o"], "gpt4o-prod");
+        assert!(!azure.deployments.contains_key("gpt-4.1"));
+        assert_eq!(
+            azure.deployment_url("gpt4o-prod", "chat/completions"),
+            "https://my-resource.openai.azure.com/openai/deployments/gpt4o-prod/chat/completions?api-version=2024-10-21"
+        );
+        assert_eq!(azure.api_key(), "azure-key");
+    }
+
+    #[test]
+    fn test_providers_and_models() {
+        let config: ReservoirConfig = toml::from_str(
+            r#"
+            [[providers]]
+            name = "groq"
+            kind = "openai"
+            base_url = "https://api.groq.com/openai/v1/chat/completions"
+            api_key_env = "GROQ_API_KEY"
+
+            [[models]]
+            name = "llama-3.3-70b-versatile"
+            provider = "groq"
+            context_window = 131072
+            tokenizer = "cl100k_base"
+            capabilities = ["tools", "streaming"]
+            aliases = ["groq-llama"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.providers[0].kind, ProviderKind::Openai);
+        let model = &config.models[0];
+        assert_eq!(model.context_window, 131_072);
+        assert_eq!(model.output_tokens, 4_096);
+        assert_eq!(model.tokenizer, Tokenizer::Cl100kBase);
+        assert_eq!(model.aliases, vec!["groq-llama"]);
+    }
+
+    #[test]
+    fn test_neo4j_database() {
+        assert_eq!(
+            resolve_neo4j_database(None, Some("reservoir")),
+            Some("reservoir".to_string())
+        );
+        assert_eq!(resolve_neo4j_database(None, None), None);
+        assert_eq!(
+            resolve_neo4j_database(None, Some("memory")),
+            Some("memory".to_string())
+        );
+        assert_eq!(
+            resolve_neo4j_database(Some("other".to_string()), Some("memory")),
+            Some("other".to_string())
+        );
+    }
+
+    #[test]
+    fn test_legacy_default_config() {
+        assert!(is_legacy_default(
+            "neo4j_uri = \"bolt://localhost:7687\"\nneo4j_user = \"neo4j\"\nneo4j_password = \"password\"\nreservoir_port = 3017\nneo4j_database = \"reservoir\"\n"
+        ));
+        assert!(!is_legacy_default(
+            "neo4j_uri = \"bolt://localhost:7687\"\nneo4j_user = \"neo4j\"\nneo4j_password = \"secret\"\nreservoir_port = 3017\nneo4j_database = \"reservoir\"\n"
+        ));
+        assert!(!is_legacy_default(&toml::to_string_pretty(&ReservoirConfig::default()).unwrap()));
+    }
+
+    #[test]
+    fn test_partition_overrides() {
+        let config: ReservoirConfig = toml::from_str(
+            r#"
+            [synapses]
+            threshold = 0.8
+
+            [enrichment]
+            similar_limit = 10
+
+            [partitions.work.synapses]
+            max_hops = 3
+
+            [partitions.work.enrichment]
+            recent_token_budget = 500
+            strategy = "graph_walk"
+            "#,
+        )
+        .unwrap();
+
+        let work = config.synapse_settings("work");
+        assert_eq!(work.threshold, 0.8);
+        assert_eq!(work.max_hops, 3);
+        assert_eq!(config.synapse_settings("home").max_hops, 10);
+
+        let work = config.enrichment_settings("work");
+        assert_eq!(work.similar_limit, 10);
+        assert_eq!(work.recent_token_budget, 500);
+        assert_eq!(work.semantic_token_budget, 4000);
+        assert_eq!(work.strategy, Strategy::GraphWalk);
+        assert_eq!(config.enrichment_settings("home").recent_token_budget, 4000);
+        assert_eq!(config.enrichment_settings("home").strategy, Strategy::Hybrid);
+    }
+}
@@ -1,10 +1,8 @@
 use anyhow::Error;
-use neo4rs::{query, ConfigBuilder, Graph};
+use neo4rs::{query, Graph};
 
 use crate::models::embedding_node::EmbeddingNode;
 
-use super::config::{get_neo4j_password, get_neo4j_uri, get_neo4j_user};
-
 pub trait EmbeddingRepository {
     async fn find_similar_embeddings(
         &self,
@@ -20,8 +18,8 @@ pub enum AnyEmbeddingRepository {
 }
 
 impl AnyEmbeddingRepository {
-    pub fn new_neo4j(uri: String, user: String, pass: String) -> Self {
-        AnyEmbeddingRepository::Neo4j(Neo4jEmbeddingRepository::new(uri, user, pass))
+    pub fn new_neo4j(graph: Graph) -> Self {
+        AnyEmbeddingRepository::Neo4j(Neo4jEmbeddingRepository::new(graph))
     }
 }
 
@@ -43,28 +41,12 @@ impl EmbeddingRepository for AnyEmbeddingRepository {
 }
 
 pub struct Neo4jEmbeddingRepository {
-    uri: String,
-    user: String,
-    pass: String,
+    graph: Graph,
 }
 
 impl Neo4jEmbeddingRepository {
-    pub fn new(uri: String, user: String, pass: String) -> Self {
-        Neo4jEmbeddingRepository {
-            uri: get_neo4j_uri(),
-            user: get_neo4j_user(),
-            pass: get_neo4j_password(),
-        }
-    }
-
-    async fn connect(&self) -> Result<Graph, Error> {
-        let config = ConfigBuilder::new()
-            .uri(self.uri.clone())
-            .user(self.user.clone())
-            .password(self.pass.clone())
-            .build()?;
-        let graph = Graph::connect(config).await?;
-        Ok(graph)
+    pub fn new(graph: Graph) -> Self {
+        Neo4jEmbeddingRepository { graph }
     }
 }
 
@@ -76,7 +58,7 @@ impl EmbeddingRepository for Neo4jEmbeddingRepository {
         instance: &str,
         top_k: usize,
     ) -> Result<Vec<EmbeddingNode>, Error> {
-        let graph = self.connect().await?;
+        let graph = &self.graph;
         let q = query(
             r#"
             MATCH (e:EmbeddingNode) 
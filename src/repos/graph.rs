@@ -0,0 +1,18 @@
+use anyhow::Error;
+use neo4rs::{ConfigBuilder, Graph};
+
+use crate::repos::config::{get_neo4j_database, get_neo4j_password, get_neo4j_uri, get_neo4j_user};
+
+/// Creates the connection pool shared by every repository. Connections are
+/// opened lazily, on the first query.
+pub async fn connect() -> Result<Graph, Error> {
+    let mut config = ConfigBuilder::new()
+        .uri(get_neo4j_uri())
+        .user(get_neo4j_user())
+        .password(get_neo4j_password());
+    if let Some(database) = get_neo4j_database() {
+        config = config.db(database);
+    }
+    let config = config.build()?;
+    Ok(Graph::connect(config).await?)
+}
@@ -1,10 +1,109 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 
+use crate::errors::ReservoirError;
 use crate::models::message_node::MessageNode;
-use crate::repos::config::{get_neo4j_password, get_neo4j_uri, get_neo4j_user};
+use crate::repos::config::{get_enrichment_settings, get_synapse_settings};
 use anyhow::Error;
 use neo4rs::*;
-use tracing::{error, info};
+use tracing::info;
+use uuid::Uuid;
+
+/// Links each matched `m` to the message it follows in its conversation,
+/// its `PARENT`.
+const PARENT_SYNAPSES: &str = r#"
+    MATCH (m)-[:PARENT]->(prev:MessageNode)
+    WHERE m.embedding IS NOT NULL AND size(m.embedding) = 1536
+      AND prev.embedding IS NOT NULL AND size(prev.embedding) = 1536
+    WITH prev, m, vector.similarity.cosine(prev.embedding, m.embedding) AS score
+    WHERE score >= $threshold
+    MERGE (prev)-[s:SYNAPSE]->(m)
+    SET s.score = score, s.kind = 'sequential'
+"#;
+
+/// Links each matched `m` without a parent, such as ingested or embedded
+/// texts, to the message of its partition and instance stored before it.
+const PRECEDING_SYNAPSES: &str = r#"
+    WHERE m.embedding IS NOT NULL AND size(m.embedding) = 1536
+      AND NOT (m)-[:PARENT]->()
+    CALL {
+        WITH m
+        MATCH (prev:MessageNode {partition: m.partition, instance: m.instance})
+        WHERE prev.embedding IS NOT NULL AND size(prev.embedding) = 1536
+          AND (prev.timestamp < m.timestamp
+               OR (prev.timestamp = m.timestamp AND prev.id < m.id))
+        RETURN prev
+        ORDER BY prev.timestamp DESC, prev.id DESC
+        LIMIT 1
+    }
+    WITH prev, m, vector.similarity.cosine(prev.embedding, m.embedding) AS score
+    WHERE score >= $threshold
+    MERGE (prev)-[s:SYNAPSE]->(m)
+    SET s.score = score, s.kind = 'sequential'
+"#;
+
+/// Links each matched `m` to its most similar messages in the same
+/// partition and instance that it is not linked to yet. The vector index
+/// searches all partitions, so more candidates than needed are fetched, see
+/// `Neo4jMessageRepository::semantic_candidates`.
+const SEMANTIC_SYNAPSES: &str = r#"
+    WHERE m.embedding IS NOT NULL AND size(m.embedding) = 1536
+    CALL db.index.vector.queryNodes('messageEmbeddings', $candidates, m.embedding)
+    YIELD node, score
+    WITH m, node, score
+    WHERE node <> m
+      AND node.partition = m.partition
+      AND node.instance = m.instance
+      AND node.trace_id <> m.trace_id
+      AND score >= $threshold
+      AND NOT (m)-[:SYNAPSE]-(node)
+    WITH m, node, score
+    ORDER BY score DESC
+    WITH m, collect({node: node, score: score})[..$neighbours] AS neighbours
+    UNWIND neighbours AS neighbour
+    WITH m, neighbour.node AS node, neighbour.score AS score
+    MERGE (m)-[s:SYNAPSE]->(node)
+    SET s.score = score, s.kind = 'semantic'
+"#;
+
+/// The properties of a new `MessageNode` `m`, set after it is created or
+/// merged. `is_new` tells a merged node apart from one already stored and
+/// is removed again.
+const MESSAGE_PROPERTIES: &str = r#"
+    m.id = $id,
+    m.trace_id = $trace_id,
+    m.content = $content,
+    m.role = $role,
+    m.timestamp = $timestamp,
+    m.partition = $partition,
+    m.instance = $instance,
+    m.embedding = $embedding,
+    m.url = $url,
+    m.parts = $parts,
+    m.tool_calls = $tool_calls,
+    m.tool_call_id = $tool_call_id,
+    m.model = $model,
+    m.switched_from = $switched_from,
+    m.chain_hash = $chain_hash,
+    m.parent_hash = $parent_hash,
+    m.is_new = true
+"#;
+
+/// Most candidates asked of the vector index for the neighbours of one
+/// message.
+const MAX_SEMANTIC_CANDIDATES: i64 = 1000;
+
+/// Scales the candidates wanted from an instance by how many of all
+/// messages are in it, since on average only that share of what the vector
+/// index returns is usable. An instance holding a tiny share of a large
+/// database can still miss neighbours once the maximum is reached.
+fn scale_candidates(wanted: usize, total: i64, in_instance: i64) -> i64 {
+    let wanted = wanted as i64;
+    if in_instance <= 0 {
+        return wanted;
+    }
+    let scaled = (wanted * total + in_instance - 1) / in_instance;
+    scaled.min(MAX_SEMANTIC_CANDIDATES).max(wanted)
+}
 
 pub trait MessageRepository {
     async fn save_message_node(&self, message_node: &MessageNode) -> Result<(), Error>;
@@ -39,11 +138,45 @@ pub trait MessageRepository {
         &self,
         nodes: &[MessageNode],
     ) -> Result<Vec<MessageNode>, Error>; // Changed return type
+    /// Nodes reachable from the node over `SYNAPSE` edges, never leaving
+    /// the partition and instance.
     async fn find_nodes_connected_to_node(
         &self,
         node: &MessageNode,
+        partition: &str,
+        instance: &str,
     ) -> Result<Vec<MessageNode>, Error>; // Changed return type
-    async fn connect_synapses(&self) -> Result<(), Error>;
+    /// Links the messages stored for a trace to the messages before them in
+    /// the partition and instance, and to the messages most similar to them,
+    /// with `SYNAPSE` edges. Synapses never cross from one partition or
+    /// instance to another.
+    async fn connect_synapses(
+        &self,
+        trace_id: &str,
+        partition: &str,
+        instance: &str,
+    ) -> Result<(), Error>;
+    /// Deletes every synapse and links all messages again, returning how
+    /// many synapses were created.
+    async fn rebuild_synapses(&self) -> Result<i64, Error>;
+    /// Deletes the synapses that link messages of different partitions or
+    /// instances and returns how many were deleted.
+    async fn delete_cross_partition_synapses(&self) -> Result<i64, Error>;
+    /// Which of the given chain hashes are already stored in the partition
+    /// and instance.
+    async fn find_stored_chain_hashes(
+        &self,
+        partition: &str,
+        instance: &str,
+        chain_hashes: &[String],
+    ) -> Result<HashSet<String>, Error>;
+    /// The conversation that leads to a message, following `PARENT` edges
+    /// back to its first message. Ordered oldest first and ending with the
+    /// message itself.
+    async fn get_conversation_path(&self, message_id: &str) -> Result<Vec<MessageNode>, Error>;
+    /// The last message of every branch of the conversation the message is
+    /// part of, oldest first.
+    async fn get_branch_leaves(&self, message_id: &str) -> Result<Vec<MessageNode>, Error>;
 }
 
 pub enum AnyMessageRepository {
@@ -51,8 +184,8 @@ pub enum AnyMessageRepository {
 }
 
 impl AnyMessageRepository {
-    pub fn new_neo4j() -> Self {
-        AnyMessageRepository::Neo4j(Neo4jMessageRepository::default())
+    pub fn new_neo4j(graph: Graph) -> Self {
+        AnyMessageRepository::Neo4j(Neo4jMessageRepository::new(graph))
     }
 }
 
@@ -126,41 +259,86 @@ impl MessageRepository for AnyMessageRepository {
     async fn find_nodes_connected_to_node(
         &self,
         node: &MessageNode,
+        partition: &str,
+        instance: &str,
     ) -> Result<Vec<MessageNode>, Error> {
         match self {
-            AnyMessageRepository::Neo4j(repo) => repo.find_nodes_connected_to_node(node).await,
+            AnyMessageRepository::Neo4j(repo) => {
+                repo.find_nodes_connected_to_node(node, partition, instance)
+                    .await
+            }
+        }
+    }
+
+    async fn connect_synapses(
+        &self,
+        trace_id: &str,
+        partition: &str,
+        instance: &str,
+    ) -> Result<(), Error> {
+        match self {
+            AnyMessageRepository::Neo4j(repo) => {
+                repo.connect_synapses(trace_id, partition, instance).await
+            }
+        }
+    }
+
+    async fn rebuild_synapses(&self) -> Result<i64, Error> {
+        match self {
+            AnyMessageRepository::Neo4j(repo) => repo.rebuild_synapses().await,
+        }
+    }
+
+    async fn delete_cross_partition_synapses(&self) -> Result<i64, Error> {
+        match self {
+            AnyMessageRepository::Neo4j(repo) => repo.delete_cross_partition_synapses().await,
+        }
+    }
+
+    async fn find_stored_chain_hashes(
+        &self,
+        partition: &str,
+        instance: &str,
+        chain_hashes: &[String],
+    ) -> Result<HashSet<String>, Error> {
+        match self {
+            AnyMessageRepository::Neo4j(repo) => {
+                repo.find_stored_chain_hashes(partition, instance, chain_hashes)
+                    .await
+            }
+        }
+    }
+
+    async fn get_conversation_path(&self, message_id: &str) -> Result<Vec<MessageNode>, Error> {
+        match self {
+            AnyMessageRepository::Neo4j(repo) => repo.get_conversation_path(message_id).await,
         }
     }
 
-    async fn connect_synapses(&self) -> Result<(), Error> {
+    async fn get_branch_leaves(&self, message_id: &str) -> Result<Vec<MessageNode>, Error> {
         match self {
-            AnyMessageRepository::Neo4j(repo) => repo.connect_synapses().await,
+            AnyMessageRepository::Neo4j(repo) => repo.get_branch_leaves(message_id).await,
         }
     }
 
 }
 
+#[derive(Clone)]
 pub struct Neo4jMessageRepository {
-    uri: String,
-    user: String,
-    pass: String,
+    graph: Graph,
 }
 
 impl Neo4jMessageRepository {
-    pub fn default() -> Self {
-        let instance = Neo4jMessageRepository {
-            uri: get_neo4j_uri(),
-            user: get_neo4j_user(),
-            pass: get_neo4j_password(),
-        };
-        instance.init_vector_index();
-        instance
+    pub fn new(graph: Graph) -> Self {
+        Neo4jMessageRepository { graph }
     }
 
+    /// Creates the vector indexes if they do not exist yet. Called once when
+    /// the server starts.
     pub async fn init_vector_index(&self) -> Result<(), Error> {
         let index_name = "messageEmbeddings";
         let emneddings_index_name = "messageEmbeddings";
-        let graph = self.connect().await?;
+        let graph = &self.graph;
         // Check if index already exists
         let check_query = query("SHOW INDEXES YIELD name RETURN name");
         let mut result = graph.execute(check_query).await?;
@@ -213,45 +391,185 @@ impl Neo4jMessageRepository {
         Ok(())
     }
 
-    async fn connect(&self) -> Result<Graph, Error> {
-        let config = ConfigBuilder::new()
-            .uri(self.uri.clone())
-            .user(self.user.clone())
-            .password(self.pass.clone())
-            .build()?;
-        let graph = Graph::connect(config).await?;
-        Ok(graph)
+    /// How many candidates to ask the vector index for when looking for the
+    /// neighbours of messages in a partition and instance.
+    async fn semantic_candidates(
+        &self,
+        partition: &str,
+        instance: &str,
+        neighbours: usize,
+    ) -> Result<i64, Error> {
+        let q = query(
+            r#"
+            MATCH (n:MessageNode)
+            WITH count(n) AS total
+            OPTIONAL MATCH (m:MessageNode {partition: $partition, instance: $instance})
+            RETURN total, count(m) AS in_instance
+            "#,
+        )
+        .param("partition", partition)
+        .param("instance", instance);
+        let mut result = self.graph.execute(q).await?;
+        let (total, in_instance) = match result.next().await? {
+            Some(row) => (row.get("total")?, row.get("in_instance")?),
+            None => (0, 0),
+        };
+        let wanted = neighbours * get_enrichment_settings(partition).oversample;
+        Ok(scale_candidates(wanted, total, in_instance))
     }
-}
 
-impl MessageRepository for Neo4jMessageRepository {
-    async fn save_message_node(&self, message_node: &MessageNode) -> Result<(), Error> {
-        // Skip saving system messages
-        if message_node.role.eq_ignore_ascii_case("system") {
-            return Ok(());
-        }
+    /// Indexes the message ids and the order of the messages of an
+    /// instance, used to link messages without a parent to the one before
+    /// them, and makes chain hashes unique within an instance, so a message
+    /// sent by two requests at once is stored once. Creating the constraint
+    /// fails while duplicates stored before it exist.
+    pub async fn init_lookup_indexes(&self) -> Result<(), Error> {
+        self.graph
+            .run(query(
+                "CREATE INDEX messageId IF NOT EXISTS FOR (m:MessageNode) ON (m.id)",
+            ))
+            .await?;
+        self.graph
+            .run(query(
+                "CREATE INDEX messageOrder IF NOT EXISTS FOR (m:MessageNode) ON (m.partition, m.instance, m.timestamp)",
+            ))
+            .await?;
+        self.graph
+            .run(query(
+                "CREATE CONSTRAINT messageChainHashUnique IF NOT EXISTS FOR (m:MessageNode) REQUIRE (m.partition, m.instance, m.chain_hash) IS UNIQUE",
+            ))
+            .await?;
+        Ok(())
+    }
 
-        let graph = self.connect().await?;
-        let create_q = query(
+    /// Chains a message to the one before it in the conversation:
+    /// `(parent)-[:NEXT]->(message)` and `(message)-[:PARENT]->(parent)`.
+    async fn link_parent(
+        &self,
+        graph: &Graph,
+        node_id: i64,
+        message_node: &MessageNode,
+    ) -> Result<(), Error> {
+        let Some(parent_hash) = &message_node.parent_hash else {
+            return Ok(());
+        };
+        let q = query(
             r#"
-            CREATE (m:MessageNode {
-                trace_id: $trace_id,
-                content: $content,
-                role: $role,
-                timestamp: $timestamp,
+            MATCH (m:MessageNode) WHERE id(m) = $node_id
+            MATCH (p:MessageNode {
+                chain_hash: $parent_hash,
                 partition: $partition,
-                instance: $instance,
-                embedding: $embedding,
-                url: $url
-            })
-            CREATE (e:EmbeddingNode {
-                model: 'text-embedding-ada-002',
-                embedding: $embedding
+                instance: $instance
             })
-            CREATE (m)-[:HAS_EMBEDDING]->(e)
-            RETURN id(m) AS nodeId, id(e) AS embeddingNodeId
-            "#
+            MERGE (m)-[:PARENT]->(p)
+            MERGE (p)-[:NEXT]->(m)
+            "#,
         )
+        .param("node_id", node_id)
+        .param("parent_hash", parent_hash.clone())
+        .param("partition", message_node.partition.clone())
+        .param("instance", message_node.instance.clone());
+        graph.run(q).await?;
+        Ok(())
+    }
+
+    /// Stores the tool calls of an assistant message as `ToolCall` nodes and
+    /// attaches tool results to the call they answer:
+    /// `(:MessageNode)-[:CALLED_TOOL]->(:ToolCall)-[:RETURNED]->(:MessageNode)`
+    async fn link_tool_calls(
+        &self,
+        graph: &Graph,
+        node_id: i64,
+        message_node: &MessageNode,
+    ) -> Result<(), Error> {
+        if let Some(calls) = message_node.get_tool_calls().filter(|c| !c.is_empty()) {
+            let ids: Vec<String> = calls.iter().map(|c| c.id.clone()).collect();
+            let names: Vec<String> = calls.iter().map(|c| c.function.name.clone()).collect();
+            let arguments: Vec<String> = calls
+                .iter()
+                .map(|c| c.function.arguments.clone())
+                .collect();
+            let q = query(
+                r#"
+                MATCH (m:MessageNode) WHERE id(m) = $node_id
+                UNWIND range(0, size($ids) - 1) AS i
+                MERGE (t:ToolCall {
+                    call_id: $ids[i],
+                    partition: $partition,
+                    instance: $instance
+                })
+                SET t.name = $names[i], t.arguments = $arguments[i]
+                MERGE (m)-[:CALLED_TOOL]->(t)
+                "#,
+            )
+            .param("node_id", node_id)
+            .param("ids", ids)
+            .param("names", names)
+            .param("arguments", arguments)
+            .param("partition", message_node.partition.clone())
+            .param("instance", message_node.instance.clone());
+            graph.run(q).await?;
+        }
+
+        if let Some(tool_call_id) = &message_node.tool_call_id {
+            let q = query(
+                r#"
+                MATCH (m:MessageNode) WHERE id(m) = $node_id
+                MERGE (t:ToolCall {
+                    call_id: $call_id,
+                    partition: $partition,
+                    instance: $instance
+                })
+                MERGE (t)-[:RETURNED]->(m)
+                "#,
+            )
+            .param("node_id", node_id)
+            .param("call_id", tool_call_id.clone())
+            .param("partition", message_node.partition.clone())
+            .param("instance", message_node.instance.clone());
+            graph.run(q).await?;
+        }
+        Ok(())
+    }
+}
+
+impl MessageRepository for Neo4jMessageRepository {
+    async fn save_message_node(&self, message_node: &MessageNode) -> Result<(), Error> {
+        // Nodes imported from exports made before messages had ids get one
+        let id = if message_node.id.is_empty() {
+            Uuid::new_v4().to_string()
+        } else {
+            message_node.id.clone()
+        };
+        let graph = &self.graph;
+        // Messages of a conversation are merged on their chain hash, so a
+        // request racing another with the same history does not store it
+        // twice. Nodes without one, such as ingested texts, are created.
+        let node = if message_node.chain_hash.is_some() {
+            format!(
+                "MERGE (m:MessageNode {{partition: $partition, instance: $instance, chain_hash: $chain_hash}}) ON CREATE SET {}",
+                MESSAGE_PROPERTIES
+            )
+        } else {
+            format!("CREATE (m:MessageNode) SET {}", MESSAGE_PROPERTIES)
+        };
+        let create_q = query(&format!(
+            r#"
+            {}
+            WITH m, m.is_new IS NOT NULL AS created
+            REMOVE m.is_new
+            FOREACH (_ IN CASE WHEN created THEN [1] ELSE [] END |
+                CREATE (e:EmbeddingNode {{
+                    model: 'text-embedding-ada-002',
+                    embedding: $embedding
+                }})
+                CREATE (m)-[:HAS_EMBEDDING]->(e)
+            )
+            RETURN id(m) AS nodeId, created
+            "#,
+            node
+        ))
+        .param("id", id)
         .param("trace_id", message_node.trace_id.clone())
         .param("content", message_node.content.clone())
         .param("timestamp", message_node.timestamp.clone())
@@ -259,12 +577,34 @@ impl MessageRepository for Neo4jMessageRepository {
         .param("partition", message_node.partition.clone())
         .param("instance", message_node.instance.clone())
         .param("embedding", message_node.embedding.clone())
-        .param("url", message_node.url.clone());
+        .param("url", message_node.url.clone())
+        .param("parts", message_node.parts.clone())
+        .param("tool_calls", message_node.tool_calls.clone())
+        .param("tool_call_id", message_node.tool_call_id.clone())
+        .param("model", message_node.model.clone())
+        .param("switched_from", message_node.switched_from.clone())
+        .param("chain_hash", message_node.chain_hash.clone())
+        .param("parent_hash", message_node.parent_hash.clone());
 
         // Execute the CREATE query
         let mut create_result = graph.execute(create_q).await?;
         // Consume the result to ensure the node is created before potentially linking it
-        let _ = create_result.next().await?;
+        let (node_id, created): (Option<i64>, bool) = match create_result.next().await? {
+            Some(row) => (row.get("nodeId").ok(), row.get("created").unwrap_or(true)),
+            None => (None, true),
+        };
+        if !created {
+            info!(
+                "Message {:?} was stored by another request in the meantime",
+                message_node.chain_hash
+            );
+            return Ok(());
+        }
+
+        if let Some(node_id) = node_id {
+            self.link_tool_calls(graph, node_id, message_node).await?;
+            self.link_parent(graph, node_id, message_node).await?;
+        }
 
         // If the saved message is an assistant message, try to link it to the corresponding user message
         if message_node.role.eq_ignore_ascii_case("assistant") {
@@ -286,7 +626,7 @@ impl MessageRepository for Neo4jMessageRepository {
     }
 
     async fn get_message_node(&self, trace_id: &str) -> Result<MessageNode, Error> {
-        let graph = self.connect().await?;
+        let graph = &self.graph;
         let q = format!(
             "MATCH (m:MessageNode {{trace_id: '{}'}}) RETURN m",
             trace_id
@@ -304,7 +644,7 @@ impl MessageRepository for Neo4jMessageRepository {
         &self,
         partition: Option<&str>,
     ) -> Result<Vec<MessageNode>, Error> {
-        let graph = self.connect().await?;
+        let graph = &self.graph;
         let q = if let Some(p) = partition {
             query("MATCH (m:MessageNode {partition: $partition}) RETURN m").param("partition", p)
         } else {
@@ -323,7 +663,7 @@ impl MessageRepository for Neo4jMessageRepository {
     }
 
     async fn delete_message_node(&self, trace_id: &str) -> Result<i32, Error> {
-        let graph = self.connect().await?;
+        let graph = &self.graph;
         let q = format!(
             "MATCH (m:MessageNode {{trace_id: '{}'}}) DELETE m RETURN COUNT(m)",
             trace_id
@@ -342,8 +682,8 @@ impl MessageRepository for Neo4jMessageRepository {
         instance: &str,
         top_k: usize,
     ) -> Result<Vec<MessageNode>, Error> {
-        let graph = self.connect().await?;
-        let top_k_extended = (top_k * 3) as i64;
+        let graph = &self.graph;
+        let top_k_extended = (top_k * get_enrichment_settings(partition).oversample) as i64;
         let query_text = "
         CALL db.index.vector.queryNodes(
             'messageEmbeddings',
@@ -354,14 +694,22 @@ impl MessageRepository for Neo4jMessageRepository {
         WHERE node.partition = $partition
           AND node.instance = $instance
           AND node.role = $role
-        RETURN node.trace_id AS trace_id,
+        RETURN coalesce(node.id, '') AS id,
+               node.trace_id AS trace_id,
                node.partition AS partition,
                node.instance AS instance,
                node.role AS role,
                node.content AS content,
                node.embedding AS embedding,
                node.url AS url,
+               node.parts AS parts,
                node.timestamp AS timestamp,
+               node.tool_calls AS tool_calls,
+               node.tool_call_id AS tool_call_id,
+               node.model AS model,
+               node.switched_from AS switched_from,
+               node.chain_hash AS chain_hash,
+               node.parent_hash AS parent_hash,
                score
         ORDER BY score DESC
     ";
@@ -379,6 +727,7 @@ impl MessageRepository for Neo4jMessageRepository {
         let mut messages: Vec<(MessageNode, f64)> = Vec::new();
         while let Ok(Some(row)) = result.next().await {
             let message = MessageNode {
+                id: row.get("id")?,
                 trace_id: row.get("trace_id")?,
                 partition: row.get("partition")?,
                 instance: row.get("instance")?,
@@ -386,7 +735,14 @@ impl MessageRepository for Neo4jMessageRepository {
                 content: row.get("content")?,
                 embedding: row.get("embedding")?,
                 url: row.get("url")?,
+                parts: row.get("parts")?,
                 timestamp: row.get("timestamp")?,
+                tool_calls: row.get("tool_calls")?,
+                tool_call_id: row.get("tool_call_id")?,
+                model: row.get("model")?,
+                switched_from: row.get("switched_from")?,
+                chain_hash: row.get("chain_hash")?,
+                parent_hash: row.get("parent_hash")?,
             };
             let score: f64 = row.get("score")?;
             messages.push((message, score));
@@ -406,9 +762,9 @@ impl MessageRepository for Neo4jMessageRepository {
         instance: String,
         count: usize,
     ) -> Result<Vec<MessageNode>, Error> {
-        let graph = self.connect().await?;
+        let graph = &self.graph;
         let q = format!(
-            "MATCH (m:MessageNode {{partition: '{}', instance: '{}'}}) RETURN m ORDER BY m.timestamp DESC LIMIT {}",
+            "MATCH (m:MessageNode {{partition: '{}', instance: '{}'}}) WHERE m.role <> 'system' RETURN m ORDER BY m.timestamp DESC LIMIT {}",
             partition, instance, count
         );
         let mut result = graph.execute(query(q.as_str())).await?;
@@ -430,7 +786,7 @@ impl MessageRepository for Neo4jMessageRepository {
 
         let trace_ids: Vec<String> = nodes.iter().map(|n| n.trace_id.clone()).collect();
 
-        let graph = self.connect().await?;
+        let graph = &self.graph;
         // Query to find pairs of connected nodes within the input list,
         // then unwind the pairs and collect the distinct nodes involved.
         let query_text = r#"
@@ -460,51 +816,251 @@ impl MessageRepository for Neo4jMessageRepository {
         Ok(connected_nodes) // Return the vector of MessageNode
     }
 
-    async fn connect_synapses(&self) -> Result<(), Error> {
-        let graph = self.connect().await?;
-        let q = r#"
-            MATCH (m:MessageNode)
-            WHERE m.embedding IS NOT NULL AND size(m.embedding) = 1536
-            WITH m
-            ORDER BY m.timestamp ASC
-            WITH collect(m) AS messages
-            UNWIND range(0, size(messages) - 2) AS i
-            WITH messages[i] AS m1, messages[i+1] AS m2
-            WHERE m1.embedding IS NOT NULL AND m2.embedding IS NOT NULL AND size(m1.embedding) = 1536 AND size(m2.embedding) = 1536
-            MERGE (m1)-[:SYNAPSE {score: vector.similarity.cosine(m1.embedding, m2.embedding)}]-(m2);
-        "#;
-        let mut result = graph.execute(query(q)).await?;
-        while let Ok(Some(row)) = result.next().await {
-            let node: MessageNode = row.get("m")?;
-            info!("Connected nodes: {:?}", node);
+    async fn connect_synapses(
+        &self,
+        trace_id: &str,
+        partition: &str,
+        instance: &str,
+    ) -> Result<(), Error> {
+        let settings = get_synapse_settings(partition);
+        // Only the new messages and the messages they are linked to are
+        // touched, the rest of the graph keeps its synapses.
+        for sequential in [PARENT_SYNAPSES, PRECEDING_SYNAPSES] {
+            let q = query(&format!(
+                r#"
+                MATCH (m:MessageNode {{trace_id: $trace_id, partition: $partition, instance: $instance}})
+                {}
+                "#,
+                sequential
+            ))
+            .param("trace_id", trace_id)
+            .param("partition", partition)
+            .param("instance", instance)
+            .param("threshold", settings.threshold);
+            self.graph.run(q).await?;
+        }
+
+        if settings.semantic_neighbours == 0 {
+            return Ok(());
+        }
+        let q = query(&format!(
+            r#"
+            MATCH (m:MessageNode {{trace_id: $trace_id, partition: $partition, instance: $instance}})
+            {}
+            "#,
+            SEMANTIC_SYNAPSES
+        ))
+        .param("trace_id", trace_id)
+        .param("partition", partition)
+        .param("instance", instance)
+        .param(
+            "candidates",
+            self.semantic_candidates(partition, instance, settings.semantic_neighbours)
+                .await?,
+        )
+        .param("neighbours", settings.semantic_neighbours as i64)
+        .param("threshold", settings.semantic_threshold);
+        self.graph.run(q).await?;
+        Ok(())
+    }
+
+    async fn rebuild_synapses(&self) -> Result<i64, Error> {
+        let graph = &self.graph;
+        graph
+            .run(query("MATCH (:MessageNode)-[r:SYNAPSE]-(:MessageNode) DELETE r"))
+            .await?;
+
+        let mut partitions = Vec::new();
+        let mut result = graph
+            .execute(query(
+                "MATCH (m:MessageNode) WHERE m.partition IS NOT NULL RETURN DISTINCT m.partition AS partition",
+            ))
+            .await?;
+        while let Some(row) = result.next().await? {
+            partitions.push(row.get::<String>("partition")?);
+        }
+
+        // Each partition may have its own thresholds
+        for partition in partitions {
+            let settings = get_synapse_settings(&partition);
+            // The same links `connect_synapses` makes as messages arrive
+            for sequential in [PARENT_SYNAPSES, PRECEDING_SYNAPSES] {
+                let q = query(&format!(
+                    r#"
+                    MATCH (m:MessageNode {{partition: $partition}})
+                    {}
+                    "#,
+                    sequential
+                ))
+                .param("partition", partition.as_str())
+                .param("threshold", settings.threshold);
+                graph.run(q).await?;
+            }
+
+            if settings.semantic_neighbours == 0 {
+                continue;
+            }
+            let mut instances = Vec::new();
+            let mut result = graph
+                .execute(
+                    query(
+                        "MATCH (m:MessageNode {partition: $partition}) RETURN DISTINCT m.instance AS instance",
+                    )
+                    .param("partition", partition.as_str()),
+                )
+                .await?;
+            while let Some(row) = result.next().await? {
+                instances.push(row.get::<String>("instance")?);
+            }
+            for instance in instances {
+                let candidates = self
+                    .semantic_candidates(&partition, &instance, settings.semantic_neighbours)
+                    .await?;
+                let q = query(&format!(
+                    r#"
+                    MATCH (m:MessageNode {{partition: $partition, instance: $instance}})
+                    {}
+                    "#,
+                    SEMANTIC_SYNAPSES
+                ))
+                .param("partition", partition.as_str())
+                .param("instance", instance.as_str())
+                .param("candidates", candidates)
+                .param("neighbours", settings.semantic_neighbours as i64)
+                .param("threshold", settings.semantic_threshold);
+                graph.run(q).await?;
+            }
+        }
+
+        let mut result = graph
+            .execute(query(
+                "MATCH (:MessageNode)-[s:SYNAPSE]->(:MessageNode) RETURN count(s) AS created",
+            ))
+            .await?;
+        let mut created = 0;
+        if let Some(row) = result.next().await? {
+            created = row.get("created")?;
         }
+        Ok(created)
+    }
+
+    async fn delete_cross_partition_synapses(&self) -> Result<i64, Error> {
         let q = r#"
             MATCH (m1:MessageNode)-[r:SYNAPSE]->(m2:MessageNode)
-            WHERE r.score < 0.85
+            WHERE coalesce(m1.partition, '') <> coalesce(m2.partition, '')
+               OR coalesce(m1.instance, '') <> coalesce(m2.instance, '')
             DELETE r
+            RETURN count(r) AS deleted
         "#;
-        let mut result = graph.execute(query(q)).await?;
-        while let Ok(Some(row)) = result.next().await {
-            let node: MessageNode = row.get("m")?;
-            error!("Deleted synapse: {:?}", node);
+        let mut result = self.graph.execute(query(q)).await?;
+        let mut deleted = 0;
+        if let Some(row) = result.next().await? {
+            deleted = row.get("deleted")?;
         }
-        Ok(())
+        Ok(deleted)
     }
 
-    /// Finds nodes connected to a given node within a distance of 10 hops.
+    async fn find_stored_chain_hashes(
+        &self,
+        partition: &str,
+        instance: &str,
+        chain_hashes: &[String],
+    ) -> Result<HashSet<String>, Error> {
+        let q = query(
+            r#"
+            MATCH (m:MessageNode {partition: $partition, instance: $instance})
+            WHERE m.chain_hash IN $chain_hashes
+            RETURN DISTINCT m.chain_hash AS chain_hash
+            "#,
+        )
+        .param("partition", partition)
+        .param("instance", instance)
+        .param("chain_hashes", chain_hashes.to_vec());
+        let mut result = self.graph.execute(q).await?;
+        let mut stored = HashSet::new();
+        while let Some(row) = result.next().await? {
+            stored.insert(row.get("chain_hash")?);
+        }
+        Ok(stored)
+    }
+
+    async fn get_conversation_path(&self, message_id: &str) -> Result<Vec<MessageNode>, Error> {
+        let q = query(
+            r#"
+            MATCH (m:MessageNode {id: $id})
+            MATCH path = (m)-[:PARENT*0..]->(first:MessageNode)
+            WHERE NOT (first)-[:PARENT]->()
+            WITH nodes(path) AS nodes
+            ORDER BY size(nodes) DESC
+            LIMIT 1
+            UNWIND range(size(nodes) - 1, 0, -1) AS i
+            RETURN nodes[i] AS m
+            "#,
+        )
+        .param("id", message_id);
+        let mut result = self.graph.execute(q).await?;
+        let mut path = Vec::new();
+        while let Some(row) = result.next().await? {
+            path.push(row.get::<MessageNode>("m")?);
+        }
+        if path.is_empty() {
+            return Err(ReservoirError::NotFound(format!("Message {} not found", message_id)).into());
+        }
+        Ok(path)
+    }
+
+    async fn get_branch_leaves(&self, message_id: &str) -> Result<Vec<MessageNode>, Error> {
+        let q = query(
+            r#"
+            MATCH (m:MessageNode {id: $id})
+            MATCH (m)-[:PARENT*0..]->(first:MessageNode)
+            WHERE NOT (first)-[:PARENT]->()
+            MATCH (first)-[:NEXT*0..]->(leaf:MessageNode)
+            WHERE NOT (leaf)-[:NEXT]->()
+            RETURN DISTINCT leaf
+            ORDER BY leaf.timestamp ASC
+            "#,
+        )
+        .param("id", message_id);
+        let mut result = self.graph.execute(q).await?;
+        let mut leaves = Vec::new();
+        while let Some(row) = result.next().await? {
+            leaves.push(row.get::<MessageNode>("leaf")?);
+        }
+        if leaves.is_empty() {
+            return Err(ReservoirError::NotFound(format!("Message {} not found", message_id)).into());
+        }
+        Ok(leaves)
+    }
+
+    /// Finds nodes connected to a given node within `max_hops` hops, 10 by
+    /// default.
     /// Returns a vector of `MessageNode` instances representing the connected nodes.
-    /// The distance is defined by the number of hops in the graph.
+    /// The distance is defined by the number of hops in the graph. Paths
+    /// that pass through another partition or instance are not followed.
     async fn find_nodes_connected_to_node(
         &self,
         node: &MessageNode,
+        partition: &str,
+        instance: &str,
     ) -> Result<Vec<MessageNode>, Error> {
-        let graph = self.connect().await?;
-        let q = r#"
-            MATCH p=(m:MessageNode {trace_id: $trace_id})-[:SYNAPSE*1..10]-(n:MessageNode)
+        let graph = &self.graph;
+        // The length of a variable path cannot be a parameter
+        let q = format!(
+            r#"
+            MATCH p=(m:MessageNode {{trace_id: $trace_id, partition: $partition, instance: $instance}})-[:SYNAPSE*1..{}]-(n:MessageNode)
+            WHERE all(x IN nodes(p) WHERE x.partition = $partition AND x.instance = $instance)
             RETURN nodes(p) AS allNodes
-        "#;
+            "#,
+            get_synapse_settings(partition).max_hops
+        );
         let mut result = graph
-            .execute(query(q).param("trace_id", node.trace_id.clone()))
+            .execute(
+                query(&q)
+                    .param("trace_id", node.trace_id.clone())
+                    .param("partition", partition)
+                    .param("instance", instance),
+            )
             .await?;
         let mut connected_nodes = Vec::new();
         while let Ok(Some(row)) = result.next().await {
@@ -519,12 +1075,15 @@ impl MessageRepository for Neo4jMessageRepository {
 mod tests {
     use super::*;
     use crate::models::message_node::MessageNode;
+    use crate::repos::graph;
+    use tracing::error;
 
     #[tokio::test]
     async fn test_save_message_node() {
-        let repo = Neo4jMessageRepository::default();
+        let repo = Neo4jMessageRepository::new(graph::connect().await.unwrap());
 
         let message_node = MessageNode {
+            id: "test-message".to_string(),
             embedding: vec![],
             trace_id: "12345".to_string(),
             partition: "default".to_string(),
@@ -532,7 +1091,14 @@ mod tests {
             role: "user".to_string(),
             content: Some("Hello, world!".to_string()),
             url: None,
+            parts: None,
             timestamp: chrono::Utc::now().timestamp_millis(),
+            tool_calls: None,
+            tool_call_id: None,
+            model: None,
+            switched_from: None,
+            chain_hash: None,
+            parent_hash: None,
         };
         let result = repo.save_message_node(&message_node).await;
         if result.is_err() {
@@ -543,7 +1109,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_messages_for_trace_id() {
-        let repo = Neo4jMessageRepository::default();
+        let repo = Neo4jMessageRepository::new(graph::connect().await.unwrap());
 
         // add some messages to get
         let mut first = MessageNode::default();
@@ -585,11 +1151,12 @@ mod tests {
 
     #[tokio::test]
     async fn test_delete_message_node() {
-        let repo = Neo4jMessageRepository::default();
+        let repo = Neo4jMessageRepository::new(graph::connect().await.unwrap());
 
         let trace_id = "test-delete-node";
         // Ensure the node exists before deleting
         let message_node = MessageNode {
+            id: "test-message".to_string(),
             embedding: vec![],
             trace_id: trace_id.to_string(),
             partition: "default".to_string(),
@@ -597,7 +1164,14 @@ mod tests {
             role: "user".to_string(),
             content: Some("To be deleted".to_string()),
             url: None,
+            parts: None,
             timestamp: chrono::Utc::now().timestamp_millis(),
+            tool_calls: None,
+            tool_call_id: None,
+            model: None,
+            switched_from: None,
+            chain_hash: None,
+            parent_hash: None,
         };
         let _ = repo.save_message_node(&message_node).await;
 
@@ -613,4 +1187,13 @@ mod tests {
             "Message node should not be found after deletion"
         );
     }
+
+    #[test]
+    fn test_scale_candidates() {
+        // A tenth of the messages are in the instance
+        assert_eq!(scale_candidates(9, 1000, 100), 90);
+        assert_eq!(scale_candidates(9, 100, 100), 9);
+        assert_eq!(scale_candidates(9, 1_000_000, 10), MAX_SEMANTIC_CANDIDATES);
+        assert_eq!(scale_candidates(9, 0, 0), 9);
+    }
 }
@@ -1,3 +1,6 @@
 pub mod message;
 pub mod embedding;
 pub mod config;
+pub mod blob;
+pub mod api_key;
+pub mod graph;
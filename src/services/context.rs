@@ -0,0 +1,184 @@
+use anyhow::Error;
+use tracing::{error, info};
+
+use crate::clients::openai::embeddings::get_embedding_for_text;
+use crate::models::message_node::MessageNode;
+use crate::models::strategy::Strategy;
+use crate::repos::message::MessageRepository;
+use crate::utils::deduplicate_message_nodes;
+
+/// What to look up for a request.
+pub struct ContextQuery<'a> {
+    /// Text the similar messages are searched for, usually the last message
+    pub search_term: &'a str,
+    pub trace_id: &'a str,
+    pub partition: &'a str,
+    pub instance: &'a str,
+    pub similar_limit: usize,
+    pub recent_limit: usize,
+}
+
+/// The messages a strategy found, injected under separate headings.
+#[derive(Debug, Default)]
+pub struct Context {
+    pub similar: Vec<MessageNode>,
+    pub recent: Vec<MessageNode>,
+}
+
+/// A way of finding the past messages that are injected into a request.
+pub trait ContextStrategy {
+    async fn find_context<R: MessageRepository>(
+        &self,
+        repo: &R,
+        query: &ContextQuery<'_>,
+    ) -> Result<Context, Error>;
+}
+
+/// Injects nothing.
+pub struct NoContext;
+
+/// Injects the most recent messages of the partition and instance.
+pub struct RecentContext;
+
+/// Injects the messages most similar to the search term.
+pub struct SemanticContext;
+
+/// Injects the messages most similar to the search term, expanded with the
+/// messages they are linked to by synapses.
+pub struct GraphWalkContext;
+
+/// Injects what `GraphWalkContext` and `RecentContext` find together.
+pub struct HybridContext;
+
+/// Looking up context should not fail a request, a failed lookup injects
+/// nothing instead.
+fn or_nothing(result: Result<Vec<MessageNode>, Error>, what: &str) -> Vec<MessageNode> {
+    result.unwrap_or_else(|e| {
+        error!("Error finding {}: {}", what, e);
+        Vec::new()
+    })
+}
+
+async fn find_similar<R: MessageRepository>(
+    repo: &R,
+    query: &ContextQuery<'_>,
+) -> Result<Vec<MessageNode>, Error> {
+    info!("Using search term: {}", query.search_term);
+    if query.search_term.is_empty() {
+        // Nothing to search for when the last message only contains media
+        return Ok(Vec::new());
+    }
+    let embedding = get_embedding_for_text(query.search_term).await?;
+    let similar = repo
+        .find_similar_messages(
+            embedding,
+            query.trace_id,
+            query.partition,
+            query.instance,
+            query.similar_limit,
+        )
+        .await;
+    Ok(deduplicate_message_nodes(or_nothing(similar, "similar messages")))
+}
+
+impl ContextStrategy for NoContext {
+    async fn find_context<R: MessageRepository>(
+        &self,
+        _repo: &R,
+        _query: &ContextQuery<'_>,
+    ) -> Result<Context, Error> {
+        Ok(Context::default())
+    }
+}
+
+impl ContextStrategy for RecentContext {
+    async fn find_context<R: MessageRepository>(
+        &self,
+        repo: &R,
+        query: &ContextQuery<'_>,
+    ) -> Result<Context, Error> {
+        let recent = repo
+            .get_last_messages_for_partition_and_instance(
+                query.partition.to_string(),
+                query.instance.to_string(),
+                query.recent_limit,
+            )
+            .await;
+        Ok(Context {
+            similar: Vec::new(),
+            recent: or_nothing(recent, "last messages"),
+        })
+    }
+}
+
+impl ContextStrategy for SemanticContext {
+    async fn find_context<R: MessageRepository>(
+        &self,
+        repo: &R,
+        query: &ContextQuery<'_>,
+    ) -> Result<Context, Error> {
+        Ok(Context {
+            similar: find_similar(repo, query).await?,
+            recent: Vec::new(),
+        })
+    }
+}
+
+impl ContextStrategy for GraphWalkContext {
+    async fn find_context<R: MessageRepository>(
+        &self,
+        repo: &R,
+        query: &ContextQuery<'_>,
+    ) -> Result<Context, Error> {
+        let mut similar = find_similar(repo, query).await?;
+        let similar_pairs = repo.find_connections_between_nodes(&similar).await?;
+        similar.extend(similar_pairs);
+
+        // Walking from the best match only finds a thread when it is linked
+        // to more than a couple of messages
+        if let Some(first) = similar.first() {
+            let nodes = repo
+                .find_nodes_connected_to_node(first, query.partition, query.instance)
+                .await?;
+            let nodes = deduplicate_message_nodes(nodes);
+            if nodes.len() > 2 {
+                similar = nodes;
+            }
+        }
+        Ok(Context {
+            similar,
+            recent: Vec::new(),
+        })
+    }
+}
+
+impl ContextStrategy for HybridContext {
+    async fn find_context<R: MessageRepository>(
+        &self,
+        repo: &R,
+        query: &ContextQuery<'_>,
+    ) -> Result<Context, Error> {
+        let walked = GraphWalkContext.find_context(repo, query).await?;
+        let recent = RecentContext.find_context(repo, query).await?;
+        Ok(Context {
+            similar: walked.similar,
+            recent: recent.recent,
+        })
+    }
+}
+
+impl ContextStrategy for Strategy {
+    async fn find_context<R: MessageRepository>(
+        &self,
+        repo: &R,
+        query: &ContextQuery<'_>,
+    ) -> Result<Context, Error> {
+        match self {
+            Strategy::None => NoContext.find_context(repo, query).await,
+            Strategy::Recent => RecentContext.find_context(repo, query).await,
+            Strategy::Semantic => SemanticContext.find_context(repo, query).await,
+            Strategy::GraphWalk => GraphWalkContext.find_context(repo, query).await,
+            Strategy::Hybrid => HybridContext.find_context(repo, query).await,
+        }
+    }
+}
@@ -1,8 +1,27 @@
+pub mod context;
+
 use anyhow::Error;
 use crate::Neo4jMessageRepository;
 use crate::repos::message::MessageRepository;
+use crate::repos::blob::BlobStore;
 
-use crate::{clients::openai::{embeddings::get_embeddings_for_text, types::ChatRequest}, models::message_node::MessageNode};
+use crate::{clients::openai::{embeddings::get_embedding_for_text, types::{ChatRequest, Message}}, models::message_node::MessageNode};
+use tracing::info;
+
+/// The messages of a request in the order they are chained, with their chain
+/// hashes. The system messages go first wherever the client put them, so
+/// they are the root of the chain.
+fn hash_chain(messages: &[Message]) -> (Vec<&Message>, Vec<String>) {
+    let (mut chained, rest): (Vec<&Message>, Vec<&Message>) = messages
+        .iter()
+        .partition(|m| m.role.eq_ignore_ascii_case("system"));
+    chained.extend(rest);
+    let mut chain_hashes: Vec<String> = Vec::with_capacity(chained.len());
+    for message in &chained {
+        chain_hashes.push(message.chain_hash(chain_hashes.last().map(String::as_str)));
+    }
+    (chained, chain_hashes)
+}
 
 pub struct ChatRequestService <'a>{
     repo: &'a Neo4jMessageRepository,
@@ -13,34 +32,107 @@ impl <'a> ChatRequestService<'a> {
         ChatRequestService { repo }
     }
 
+    /// Stores the messages of the request that are not stored yet. Clients
+    /// send the whole conversation every turn, so the messages are matched
+    /// to stored ones by their chain hash and only the new ones, usually
+    /// just the last, are embedded and saved. The system messages root the
+    /// chain, so the same conversation under another system prompt is
+    /// stored apart, while a system prompt shared by many conversations is
+    /// stored once. Returns the chain hash of the last message, which the
+    /// answer follows.
     pub async fn save_chat_request(
         &self,
         chat_request: &ChatRequest,
         trace_id: &str,
         partition: &str,
         instance: &str,
-    ) -> Result<(), Error> {
-        for message in &chat_request.messages {
-            let embedding = get_embeddings_for_text(message.content.as_str())
-                .await?
-                .first()
-                .unwrap()
-                .embedding
-                .clone();
-            let node = MessageNode::from_message(message, trace_id, partition, instance, embedding);
-            self.repo.save_message_node(&node).await?;
+    ) -> Result<Option<String>, Error> {
+        let (messages, mut chain_hashes) = hash_chain(&chat_request.messages);
+        let stored = self
+            .repo
+            .find_stored_chain_hashes(partition, instance, &chain_hashes)
+            .await?;
+
+        // A chain hash covers every message before it, so the stored
+        // messages are always a prefix of the request. New messages hang
+        // off the end of that prefix, which makes an edited or regenerated
+        // message a sibling branch of the one it replaces.
+        let prefix = chain_hashes
+            .iter()
+            .take_while(|hash| stored.contains(*hash))
+            .count();
+        info!(
+            "{} of {} messages in trace {} are already stored",
+            prefix,
+            messages.len(),
+            trace_id
+        );
+        if prefix > 0 && prefix < messages.len() {
+            info!(
+                "Continuing conversation after stored message {} in trace {}",
+                chain_hashes[prefix - 1], trace_id
+            );
+        }
+
+        for (index, message) in messages.iter().enumerate().skip(prefix) {
+            let parent_hash = index.checked_sub(1).map(|parent| chain_hashes[parent].as_str());
+            self.save_message(message, &chain_hashes[index], parent_hash, trace_id, partition, instance)
+                .await?;
         }
-        Ok(())
+        Ok(chain_hashes.pop())
     }
 
-    pub async fn find_similar_messages(
+    async fn save_message(
         &self,
-        embedding: Vec<f32>,
+        message: &Message,
+        chain_hash: &str,
+        parent_hash: Option<&str>,
         trace_id: &str,
         partition: &str,
         instance: &str,
-        top_k: usize
-    ) -> Result<Vec<MessageNode>, Error> {
-        self.repo.find_similar_messages(embedding, trace_id, partition, instance, top_k).await
+    ) -> Result<(), Error> {
+        // Only the text parts are embedded, a message that is only an
+        // image is stored without an embedding
+        let text = message.searchable_text();
+        let embedding = if text.is_empty() {
+            Vec::new()
+        } else {
+            get_embedding_for_text(text.as_str()).await?
+        };
+        let mut node = MessageNode::from_message(message, trace_id, partition, instance, embedding);
+        if let Some(parts) = message.parts.as_ref().filter(|_| message.has_media()) {
+            let parts = BlobStore::default().put_parts(parts).await?;
+            node.parts = Some(serde_json::to_string(&parts)?);
+        }
+        node.chain_hash = Some(chain_hash.to_string());
+        node.parent_hash = parent_hash.map(str::to_string);
+        self.repo.save_message_node(&node).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_system_prompt_roots_the_chain() {
+        let pirate = [message("system", "Talk like a pirate"), message("user", "Hello")];
+        let poet = [message("system", "Answer in verse"), message("user", "Hello")];
+        let (_, pirate_hashes) = hash_chain(&pirate);
+        let (_, poet_hashes) = hash_chain(&poet);
+        assert_ne!(pirate_hashes[1], poet_hashes[1]);
+
+        let late_system = [message("user", "Hello"), message("system", "Talk like a pirate")];
+        let (chained, hashes) = hash_chain(&late_system);
+        assert_eq!(chained[0].role, "system");
+        assert_eq!(hashes, pirate_hashes);
     }
 }
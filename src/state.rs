@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Error;
+use neo4rs::Graph;
+
+use crate::repos::api_key::{ApiKeyRepository, Neo4jApiKeyRepository};
+use crate::repos::graph;
+use crate::repos::message::{AnyMessageRepository, Neo4jMessageRepository};
+
+/// State shared by every request and command: one pooled connection to
+/// Neo4j, created at startup. Repositories are cheap views onto it.
+#[derive(Clone)]
+pub struct AppState {
+    graph: Graph,
+    /// Whether any Reservoir API key exists, so that requests without a key
+    /// do not need to ask Neo4j. Until it is known keys are assumed to exist.
+    api_keys_exist: Arc<AtomicBool>,
+}
+
+impl AppState {
+    pub async fn connect() -> Result<Self, Error> {
+        Ok(AppState {
+            graph: graph::connect().await?,
+            api_keys_exist: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    pub fn api_keys_exist(&self) -> bool {
+        self.api_keys_exist.load(Ordering::Relaxed)
+    }
+
+    /// Looks up again whether any API key exists. Keys are managed with
+    /// `reservoir keys` from another process, so the server does this
+    /// periodically.
+    pub async fn refresh_api_keys(&self) -> Result<(), Error> {
+        let exist = self.api_key_repo().has_api_keys().await?;
+        self.api_keys_exist.store(exist, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Records that a key exists, for when a client sent a valid one.
+    pub fn mark_api_keys_exist(&self) {
+        self.api_keys_exist.store(true, Ordering::Relaxed);
+    }
+
+    pub fn message_repo(&self) -> Neo4jMessageRepository {
+        Neo4jMessageRepository::new(self.graph.clone())
+    }
+
+    pub fn any_message_repo(&self) -> AnyMessageRepository {
+        AnyMessageRepository::new_neo4j(self.graph.clone())
+    }
+
+    pub fn api_key_repo(&self) -> Neo4jApiKeyRepository {
+        Neo4jApiKeyRepository::new(self.graph.clone())
+    }
+}
@@ -1,7 +1,8 @@
 use anyhow::Error;
 use std::collections::HashSet;
 
-use tiktoken_rs::o200k_base;
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::{cl100k_base, o200k_base, CoreBPE};
 use tracing::{error, info};
 
 use crate::{clients::openai::types::{ChatRequest, Message}, models::message_node::MessageNode};
@@ -29,7 +30,7 @@ pub fn compress_system_context(messages: &Vec<Message>) -> Vec<Message> {
         for i in first + 1..=last {
             let msg = &messages[i];
             let line = format!("\n{}", message_to_string(msg));
-            compressed[0].content += &line;
+            compressed[0].push_text(&line);
         }
 
         // Add the remaining messages (after the last system prompt)
@@ -41,6 +42,14 @@ pub fn compress_system_context(messages: &Vec<Message>) -> Vec<Message> {
     }
 }
 
+/// Hex encoded SHA-256 of the given bytes.
+pub fn sha256_hex(data: &[u8]) -> String {
+    openssl::sha::sha256(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 pub fn deduplicate_message_nodes(message_nodes: Vec<MessageNode>) -> Vec<MessageNode> {
     let mut unique_nodes = HashSet::new();
     let mut deduplicated = Vec::new();
@@ -53,8 +62,26 @@ pub fn deduplicate_message_nodes(message_nodes: Vec<MessageNode>) -> Vec<Message
     deduplicated
 }
 
-pub fn count_chat_tokens(messages: &[Message]) -> usize {
-    let bpe = o200k_base().unwrap(); // Or handle error appropriately
+/// The encoding used to estimate how many tokens a model sees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+    #[default]
+    O200kBase,
+    Cl100kBase,
+}
+
+impl Tokenizer {
+    fn bpe(&self) -> CoreBPE {
+        match self {
+            Tokenizer::O200kBase => o200k_base().unwrap(),
+            Tokenizer::Cl100kBase => cl100k_base().unwrap(),
+        }
+    }
+}
+
+pub fn count_chat_tokens(messages: &[Message], tokenizer: Tokenizer) -> usize {
+    let bpe = tokenizer.bpe();
     let mut num_tokens = 0;
     for message in messages {
         num_tokens += 4; // Every message follows <|start|>{role/name}\n{content}<|end|>\n
@@ -67,8 +94,8 @@ pub fn count_chat_tokens(messages: &[Message]) -> usize {
 
 // Helper function to estimate tokens for a single chat message
 // Slightly simplified version of count_chat_tokens for one message
-pub fn count_single_message_tokens(message: &Message) -> usize {
-    let bpe = o200k_base().unwrap(); // Or handle error appropriately
+pub fn count_single_message_tokens(message: &Message, tokenizer: Tokenizer) -> usize {
+    let bpe = tokenizer.bpe();
     let mut num_tokens = 0;
     num_tokens += 4; // Overhead for message structure
     num_tokens += bpe.encode_with_special_tokens(&message.role).len();
@@ -77,8 +104,30 @@ pub fn count_single_message_tokens(message: &Message) -> usize {
     num_tokens
 }
 
-pub fn truncate_messages_if_needed(messages: &mut Vec<Message>, limit: usize) {
-    let mut current_tokens = count_chat_tokens(messages);
+/// Keeps the nodes, in their order, that fit in `budget` tokens together.
+/// A node too large for what is left of the budget is skipped rather than
+/// ending the selection, so a few long messages cannot push out all others.
+pub fn fit_token_budget(nodes: Vec<MessageNode>, budget: usize, tokenizer: Tokenizer) -> Vec<MessageNode> {
+    let bpe = tokenizer.bpe();
+    let mut remaining = budget;
+    nodes
+        .into_iter()
+        .filter(|node| {
+            let message = node.to_context_message();
+            let tokens = 4
+                + bpe.encode_with_special_tokens(&message.role).len()
+                + bpe.encode_with_special_tokens(&message.content).len();
+            if tokens > remaining {
+                return false;
+            }
+            remaining -= tokens;
+            true
+        })
+        .collect()
+}
+
+pub fn truncate_messages_if_needed(messages: &mut Vec<Message>, limit: usize, tokenizer: Tokenizer) {
+    let mut current_tokens = count_chat_tokens(messages, tokenizer);
     info!("Current token count: {}", current_tokens);
 
     if current_tokens <= limit {
@@ -128,7 +177,7 @@ pub fn truncate_messages_if_needed(messages: &mut Vec<Message>, limit: usize) {
             // Recalculate tokens and update system/last indices if needed (though less efficient)
             // For simplicity here, we just recalculate tokens. A more optimized approach
             // might update indices, but given the context size, recalculating tokens is okay.
-            current_tokens = count_chat_tokens(messages);
+            current_tokens = count_chat_tokens(messages, tokenizer);
             // Re-evaluate system_message_indices and last_message_index is safer if indices change significantly,
             // but let's stick to the simpler approach for now. If performance becomes an issue, optimize this.
         } else {
@@ -150,12 +199,36 @@ pub fn truncate_messages_if_needed(messages: &mut Vec<Message>, limit: usize) {
 
 pub fn get_last_message_in_chat_request(chat_request: &ChatRequest) -> Result<&str, Error> {
     if let Some(last_message) = chat_request.messages.last() {
-        if last_message.role == "user" {
+        // Agent loops send the tool results back as the newest message
+        if last_message.role == "user" || last_message.role == "tool" {
             Ok(&last_message.content)
         } else {
-            Err(Error::msg("Last message is not a user message"))
+            Err(Error::msg("Last message is not a user or tool message"))
         }
     } else {
         Err(Error::msg("No messages in chat request"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(content: &str) -> MessageNode {
+        MessageNode {
+            role: "user".to_string(),
+            content: Some(content.to_string()),
+            ..MessageNode::default()
+        }
+    }
+
+    #[test]
+    fn test_fit_token_budget_skips_large_messages() {
+        let nodes = vec![node("short one"), node(&"long ".repeat(500)), node("short two")];
+
+        let kept = fit_token_budget(nodes, 100, Tokenizer::default());
+
+        let contents: Vec<_> = kept.iter().map(|n| n.content.clone().unwrap()).collect();
+        assert_eq!(contents, vec!["short one", "short two"]);
+    }
+}